@@ -19,6 +19,24 @@ enum Commands {
         /// Path to config.json
         #[arg(short = 'c', long = "config", default_value = "config.yaml")]
         config: PathBuf,
+        /// Refresh policy for cached URL sources (never, always, conditional)
+        #[arg(long = "refresh")]
+        refresh: Option<String>,
+        /// Fail instead of downloading URL sources that are not cached
+        #[arg(long = "offline")]
+        offline: bool,
+        /// Override a config value, e.g. steps.go-sample.env.WL_WORKERS=32 (repeatable)
+        #[arg(long = "set", value_name = "PATH=VALUE")]
+        set: Vec<String>,
+        /// Measured runs of the whole config (overrides trials.repeat)
+        #[arg(long = "repeat")]
+        repeat: Option<u32>,
+        /// Discarded runs before the measured ones (overrides trials.warmup)
+        #[arg(long = "warmup")]
+        warmup: Option<u32>,
+        /// Pause between runs, e.g. 30s (overrides trials.cooldown)
+        #[arg(long = "cooldown")]
+        cooldown: Option<String>,
     },
     /// Generate local sample runtimes
     Samples {
@@ -33,7 +51,25 @@ pub fn run() -> Result<(), Box<dyn std::error::Error>> {
 
     match cli.command {
         Commands::List => orchestrator::list_available(),
-        Commands::Gen { config } => orchestrator::generate(&config),
+        Commands::Gen {
+            config,
+            refresh,
+            offline,
+            set,
+            repeat,
+            warmup,
+            cooldown,
+        } => orchestrator::generate(
+            &config,
+            &orchestrator::GenerateOptions {
+                refresh,
+                offline,
+                overrides: set,
+                repeat,
+                warmup,
+                cooldown,
+            },
+        ),
         Commands::Samples { output } => orchestrator::samples(&output),
     }
 }
@@ -0,0 +1,88 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Numbers staging directories of unpacks running in this process.
+static STAGING: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+pub(crate) enum ArchiveKind {
+    TarGz,
+    Tar,
+    Zip,
+}
+
+impl ArchiveKind {
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            ArchiveKind::TarGz => "tar.gz",
+            ArchiveKind::Tar => "tar",
+            ArchiveKind::Zip => "zip",
+        }
+    }
+}
+
+/// Detects an archive by the suffix of a path or URL (query and fragment are ignored).
+pub(crate) fn archive_kind(location: &str) -> Option<ArchiveKind> {
+    let path = location
+        .split(['?', '#'])
+        .next()
+        .unwrap_or(location)
+        .to_lowercase();
+    if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+        Some(ArchiveKind::TarGz)
+    } else if path.ends_with(".tar") {
+        Some(ArchiveKind::Tar)
+    } else if path.ends_with(".zip") {
+        Some(ArchiveKind::Zip)
+    } else {
+        None
+    }
+}
+
+/// Unpacks `archive` into `dest` once and returns the source root. An archive
+/// holding a single top-level directory is rooted at that directory.
+pub(crate) fn unpack_archive(
+    archive: &Path,
+    kind: ArchiveKind,
+    dest: &Path,
+) -> Result<PathBuf, Box<dyn Error>> {
+    if !dest.exists() {
+        // Each unpack stages into a directory of its own; when steps race,
+        // the first rename wins and the others discard their copy.
+        let staging = dest.with_extension(format!(
+            "partial.{}.{}",
+            std::process::id(),
+            STAGING.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&staging)?;
+        let unpacked = unpack_into(archive, kind, &staging)
+            .and_then(|()| std::fs::rename(&staging, dest).map_err(Into::into));
+        if unpacked.is_err() {
+            let _ = std::fs::remove_dir_all(&staging);
+            if !dest.exists() {
+                unpacked?;
+            }
+        }
+    }
+
+    let mut entries = std::fs::read_dir(dest)?.collect::<Result<Vec<_>, _>>()?;
+    if entries.len() == 1 && entries[0].file_type()?.is_dir() {
+        return Ok(entries.remove(0).path());
+    }
+    Ok(dest.to_path_buf())
+}
+
+fn unpack_into(archive: &Path, kind: ArchiveKind, dir: &Path) -> Result<(), Box<dyn Error>> {
+    let reader = BufReader::new(File::open(archive)?);
+    match kind {
+        ArchiveKind::TarGz => {
+            tar::Archive::new(flate2::read::GzDecoder::new(reader)).unpack(dir)?
+        }
+        ArchiveKind::Tar => tar::Archive::new(reader).unpack(dir)?,
+        ArchiveKind::Zip => zip::ZipArchive::new(reader)?.extract(dir)?,
+    }
+    Ok(())
+}
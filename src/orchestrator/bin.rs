@@ -1,21 +1,41 @@
-use crate::orchestrator::config::{
-    ConfigError, Step, step_duration_ms, step_env, step_processes, step_stdout,
-};
-use crate::orchestrator::process::{kill_process, spawn_process, wait_process};
+use crate::orchestrator::cache::CacheContext;
+use crate::orchestrator::config::{ConfigError, Step, step_env, step_processes, step_stdout};
+use crate::orchestrator::controls::StepControls;
+use crate::orchestrator::process::PendingProcess;
+use crate::orchestrator::source::resolve_source;
+use crate::orchestrator::templating::{ProcessVars, expand_process_tokens};
 use crate::orchestrator::wrapper::wrap_command;
-use crate::orchestrator::StepOutcome;
 use std::error::Error;
-use std::thread;
-use std::time::Duration;
+use std::path::Path;
 
-pub(crate) fn run(step: &Step, on_start: &dyn Fn(&[u32])) -> Result<StepOutcome, Box<dyn Error>> {
-    let exec = step
-        .exec
-        .as_deref()
-        .ok_or_else(|| ConfigError("bin runtime requires 'exec'".to_string()))?;
+pub(crate) fn launch(
+    step: &Step,
+    cache: &CacheContext,
+    controls: &StepControls,
+) -> Result<Vec<PendingProcess>, Box<dyn Error>> {
+    // With a location (directory or archive bundle), 'entry' names the executable.
+    let source = match &step.location {
+        Some(_) => Some(resolve_source(step, Path::new(""), "bin", cache)?),
+        None => None,
+    };
+    let exec = match &source {
+        Some(source) => source.path.to_string_lossy().to_string(),
+        None => {
+            let exec = step
+                .exec
+                .clone()
+                .ok_or_else(|| ConfigError("bin runtime requires 'exec'".to_string()))?;
+            // A relative path is relative to where `wl` runs, not the step's cwd;
+            // bare names are still looked up on PATH.
+            if exec.contains('/') {
+                std::path::absolute(&exec)?.to_string_lossy().to_string()
+            } else {
+                exec
+            }
+        }
+    };
     let processes = step_processes(step);
     let stdout_enabled = step_stdout(step);
-    let duration_ms = step_duration_ms(step);
     let envs = step_env(step);
 
     let args: &[String] = step.args.as_deref().unwrap_or(&[]);
@@ -23,46 +43,30 @@ pub(crate) fn run(step: &Step, on_start: &dyn Fn(&[u32])) -> Result<StepOutcome,
 
     let step_id = step.id.as_deref().unwrap_or("unknown");
     let log_label = format!("step={} runtime=bin", step_id);
-    let mut base = vec![exec.to_string()];
+    let mut base = vec![exec];
     base.extend(args.iter().cloned());
 
-    let mut children = Vec::new();
-    let mut pids = Vec::new();
-    for _ in 0..processes {
-        let wrapped = wrap_command(step, &base);
+    let mut pending = Vec::new();
+    for proc_index in 0..processes {
+        let vars = ProcessVars {
+            step_id,
+            run_dir: &cache.run_dir,
+            proc_index,
+        };
+        let wrapped = wrap_command(step, &base, &vars)?;
         let mut command = wrapped.command;
+        let settings = controls.prepare(&mut command, &vars)?;
         for (key, value) in &envs {
-            command.env(key, value);
-        }
-        let child = spawn_process(command, &log_label, &wrapped.display, stdout_enabled)?;
-        pids.push(child.pid());
-        children.push(child);
-    }
-
-    on_start(&pids);
-
-    if let Some(duration) = duration_ms {
-        thread::sleep(Duration::from_millis(duration));
-        for child in &mut children {
-            let _ = kill_process(child);
+            command.env(key, expand_process_tokens(value, &vars));
         }
+        pending.push(PendingProcess {
+            command,
+            log_label: log_label.clone(),
+            settings,
+            display: wrapped.display,
+            stdout_enabled,
+        });
     }
 
-    let mut exit_codes = Vec::new();
-    for child in children {
-        let code = wait_process(child, &log_label, duration_ms.is_some())?;
-        exit_codes.push(code);
-    }
-
-    println!("pids={}", join_pids(&pids));
-    println!("bin: done");
-
-    Ok(StepOutcome { pids, exit_codes })
-}
-
-fn join_pids(pids: &[u32]) -> String {
-    pids.iter()
-        .map(|pid| pid.to_string())
-        .collect::<Vec<_>>()
-        .join(",")
+    Ok(pending)
 }
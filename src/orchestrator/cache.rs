@@ -1,12 +1,20 @@
 use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io;
 use std::path::{Path, PathBuf};
 
 #[derive(Clone)]
 pub(crate) struct CacheContext {
     pub(crate) config_hash: String,
     pub(crate) base_dir: PathBuf,
+    pub(crate) run_id: String,
+    /// Per-run output directory: `runs/<run-id>/`.
+    pub(crate) run_dir: PathBuf,
     pub(crate) source_dir: PathBuf,
     pub(crate) url_dir: PathBuf,
+    pub(crate) git_dir: PathBuf,
+    pub(crate) refresh: Option<String>,
+    pub(crate) offline: bool,
 }
 
 impl CacheContext {
@@ -17,6 +25,35 @@ impl CacheContext {
             .join(format!("source.{extension}"))
     }
 
+    pub(crate) fn url_meta_path(&self, url: &str) -> PathBuf {
+        let url_hash = hash_string(url);
+        self.url_dir.join(url_hash).join("meta.json")
+    }
+
+    pub(crate) fn inline_source_path(&self, content: &str, extension: &str) -> PathBuf {
+        self.source_dir
+            .join(hash_string(content))
+            .join(format!("source.{extension}"))
+    }
+
+    /// Directory for an installed dependency set; order of `specs` does not matter.
+    pub(crate) fn deps_path(&self, kind: &str, specs: &[String]) -> PathBuf {
+        let mut sorted = specs.to_vec();
+        sorted.sort();
+        self.base_dir
+            .join("deps")
+            .join(kind)
+            .join(hash_string(&sorted.join("\n")))
+    }
+
+    pub(crate) fn git_mirror_path(&self, repo: &str) -> PathBuf {
+        self.git_dir.join(hash_string(repo)).join("mirror")
+    }
+
+    pub(crate) fn git_checkout_path(&self, repo: &str, commit: &str) -> PathBuf {
+        self.git_dir.join(hash_string(repo)).join(commit)
+    }
+
     pub(crate) fn config_source_path(&self, url: &str, extension: &str) -> PathBuf {
         let url_hash = hash_string(url);
         self.source_dir
@@ -24,34 +61,127 @@ impl CacheContext {
             .join(format!("source.{extension}"))
     }
 
+    pub(crate) fn unpack_path_for_archive(&self, archive: &Path) -> PathBuf {
+        let mut key = archive.to_string_lossy().to_string();
+        if let Ok(bytes) = std::fs::read(archive) {
+            key.push_str(&hash_bytes(&bytes));
+        }
+        self.source_dir.join(hash_string(&key)).join("unpacked")
+    }
+
     pub(crate) fn build_path_for_source(&self, source_path: &Path) -> PathBuf {
-        let key = source_path.to_string_lossy();
+        // Key on content as well as path so a refreshed URL source or an
+        // edited package directory is rebuilt.
+        let mut key = source_path.to_string_lossy().to_string();
+        if let Some(hash) = self.content_hash(source_path) {
+            key.push_str(&hash);
+        }
         let hash = hash_string(&key);
         self.source_dir.join(hash).join("build")
     }
+
+    /// Hash of a file, or of every path and file under a directory (the
+    /// workspace itself excluded).
+    pub(crate) fn content_hash(&self, path: &Path) -> Option<String> {
+        let mut hasher = Sha256::new();
+        if path.is_dir() {
+            hash_tree(path, path, &self.base_dir, &mut hasher).ok()?;
+        } else {
+            hasher.update(std::fs::read(path).ok()?);
+        }
+        Some(bytes_to_hex(&hasher.finalize()))
+    }
 }
 
-pub(crate) fn cache_context(config_content: &str) -> CacheContext {
+pub(crate) fn cache_context(
+    config_content: &str,
+    refresh: Option<String>,
+    offline: bool,
+) -> CacheContext {
     let config_hash = hash_string(config_content);
     let base_dir = cache_base_dir().join("tmp_workspace");
     let source_dir = base_dir.join("source");
     let url_dir = base_dir.join("url");
+    let git_dir = base_dir.join("git");
+    let run_id = format!("{}-{}", unix_millis(), &config_hash[..8]);
+    let run_dir = base_dir.join("runs").join(&run_id);
 
     CacheContext {
         config_hash,
         base_dir,
+        run_id,
+        run_dir,
         source_dir,
         url_dir,
+        git_dir,
+        refresh,
+        offline,
+    }
+}
+
+/// Id and directory for a sweep's combined summary: `sweeps/<sweep-id>/`.
+pub(crate) fn sweep_dir(config_content: &str) -> (String, PathBuf) {
+    let config_hash = hash_string(config_content);
+    let sweep_id = format!("{}-{}", unix_millis(), &config_hash[..8]);
+    let dir = cache_base_dir()
+        .join("tmp_workspace")
+        .join("sweeps")
+        .join(&sweep_id);
+    (sweep_id, dir)
+}
+
+/// Holds an exclusive lock on `<path>.lock` until dropped, so steps (and
+/// concurrent `wl` runs) fill one cache entry at a time.
+pub(crate) fn lock_entry(path: &Path) -> io::Result<File> {
+    let lock_path = path.with_extension("lock");
+    if let Some(parent) = lock_path.parent() {
+        std::fs::create_dir_all(parent)?;
     }
+    let file = File::create(lock_path)?;
+    file.lock()?;
+    Ok(file)
+}
+
+fn hash_tree(root: &Path, dir: &Path, skip: &Path, hasher: &mut Sha256) -> io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if path == skip {
+            continue;
+        }
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        hasher.update(relative.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        if entry.file_type()?.is_dir() {
+            hash_tree(root, &path, skip, hasher)?;
+        } else {
+            let bytes = std::fs::read(&path)?;
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(&bytes);
+        }
+    }
+    Ok(())
 }
 
 fn cache_base_dir() -> PathBuf {
     std::env::current_dir().unwrap_or_else(|_| std::env::temp_dir())
 }
 
+fn unix_millis() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
 fn hash_string(value: &str) -> String {
+    hash_bytes(value.as_bytes())
+}
+
+fn hash_bytes(value: &[u8]) -> String {
     let mut hasher = Sha256::new();
-    hasher.update(value.as_bytes());
+    hasher.update(value);
     bytes_to_hex(&hasher.finalize())
 }
 
@@ -0,0 +1,609 @@
+use crate::orchestrator::config::ConfigError;
+use crate::orchestrator::templating::rename_step_refs;
+use serde_yaml::{Mapping, Value};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Step keys that cannot come from `defaults` or a template.
+const STEP_ONLY_KEYS: &[&str] = &["id", "extends"];
+
+/// Top-level sections `--set` edits before composition; other paths are
+/// applied to the composed config.
+const PRE_COMPOSE_SECTIONS: &[&str] = &["defaults", "templates"];
+
+/// Loads `path` with its includes, composes it and applies `--set` overrides.
+/// The result is the fully merged config the run (and its hash) is based on.
+pub(crate) fn load_composed(path: &Path, overrides: &[String]) -> Result<Value, Box<dyn Error>> {
+    let overrides = overrides
+        .iter()
+        .map(|item| parse_override(item))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (early, late): (Vec<_>, Vec<_>) = overrides
+        .into_iter()
+        .partition(|(path, _)| PRE_COMPOSE_SECTIONS.contains(&path[0].as_str()));
+
+    let mut root = read_yaml(path)?;
+    for (path, value) in early {
+        set_path(&mut root, &path, value, "--set")?;
+    }
+    let mut stack = vec![canonical(path)?];
+    let mut root = compose_file(root, &mut stack)?;
+    for (path, value) in late {
+        set_path(&mut root, &path, value, "--set")?;
+    }
+    Ok(root)
+}
+
+fn read_yaml(path: &Path) -> Result<Value, Box<dyn Error>> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| ConfigError(format!("failed to read '{}': {}", path.display(), err)))?;
+    serde_yaml::from_str(&content)
+        .map_err(|err| Box::new(ConfigError(format!("{}: {}", path.display(), err))) as _)
+}
+
+fn canonical(path: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    fs::canonicalize(path).map_err(|err| {
+        Box::new(ConfigError(format!(
+            "failed to read '{}': {}",
+            path.display(),
+            err
+        ))) as _
+    })
+}
+
+/// Composes one file, then appends the steps of its includes. `stack` holds
+/// the files being loaded and catches include cycles.
+fn compose_file(mut root: Value, stack: &mut Vec<PathBuf>) -> Result<Value, Box<dyn Error>> {
+    let includes = match root.as_mapping_mut().and_then(|map| map.remove("include")) {
+        Some(includes) => include_list(&includes)?,
+        None => Vec::new(),
+    };
+    let mut root = compose_config(root)?;
+    let base_dir = stack
+        .last()
+        .and_then(|path| path.parent())
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+    for include in includes {
+        let path = canonical(&base_dir.join(&include.path))?;
+        if stack.contains(&path) {
+            return Err(Box::new(ConfigError(format!(
+                "include cycle: {} -> {}",
+                stack
+                    .last()
+                    .map(|last| last.display().to_string())
+                    .unwrap_or_default(),
+                path.display()
+            ))));
+        }
+        let namespace = match include.namespace {
+            Some(namespace) => namespace,
+            None => path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        };
+
+        stack.push(path.clone());
+        let included = compose_file(read_yaml(&path)?, stack)?;
+        stack.pop();
+        merge_included(&mut root, included, &namespace)?;
+    }
+    Ok(root)
+}
+
+struct Include {
+    path: String,
+    namespace: Option<String>,
+}
+
+/// Reads `include` as a path, a list of paths, or `{path, as}` entries.
+fn include_list(value: &Value) -> Result<Vec<Include>, Box<dyn Error>> {
+    let entries = match value {
+        Value::Null => return Ok(Vec::new()),
+        Value::Sequence(entries) => entries.clone(),
+        other => vec![other.clone()],
+    };
+    entries
+        .iter()
+        .map(|entry| match entry {
+            Value::String(path) => Ok(Include {
+                path: path.clone(),
+                namespace: None,
+            }),
+            Value::Mapping(map) => {
+                let path = map
+                    .get("path")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| ConfigError("include entries need a 'path'".to_string()))?;
+                let namespace = match map.get("as") {
+                    None => None,
+                    Some(Value::String(namespace)) => Some(namespace.clone()),
+                    Some(_) => {
+                        return Err(Box::new(ConfigError(format!(
+                            "include '{}': 'as' must be a string",
+                            path
+                        ))) as _);
+                    }
+                };
+                Ok(Include {
+                    path: path.to_string(),
+                    namespace,
+                })
+            }
+            _ => Err(Box::new(ConfigError(
+                "include must be a path or a list of paths".to_string(),
+            )) as _),
+        })
+        .collect()
+}
+
+/// Adds the steps of an included config under `namespace` (`<namespace>.<id>`,
+/// or the bare id when the namespace is empty). Its vars fill in names the
+/// including file does not set.
+fn merge_included(
+    root: &mut Value,
+    included: Value,
+    namespace: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut included = match included {
+        Value::Mapping(included) => included,
+        _ => {
+            return Err(Box::new(ConfigError(
+                "included config must be a mapping".to_string(),
+            )));
+        }
+    };
+    let root = root
+        .as_mapping_mut()
+        .ok_or_else(|| ConfigError("config must be a mapping".to_string()))?;
+
+    if included.contains_key("phases") {
+        return Err(Box::new(ConfigError(format!(
+            "included config '{}' must not define phases",
+            namespace
+        ))));
+    }
+    if let Some(Value::Mapping(vars)) = included.remove("vars") {
+        let root_vars = root
+            .entry(Value::from("vars"))
+            .or_insert_with(|| Value::Mapping(Mapping::new()));
+        if let Value::Mapping(root_vars) = root_vars {
+            for (key, value) in vars {
+                root_vars.entry(key).or_insert(value);
+            }
+        }
+    }
+
+    let mut steps = match included.remove("steps") {
+        Some(Value::Sequence(steps)) => steps,
+        _ => Vec::new(),
+    };
+    let ids = steps
+        .iter()
+        .filter_map(|step| step.get("id").and_then(Value::as_str))
+        .map(str::to_string)
+        .collect::<HashSet<_>>();
+    let rename = |id: &str| {
+        ids.contains(id).then(|| match namespace {
+            "" => id.to_string(),
+            namespace => format!("{}.{}", namespace, id),
+        })
+    };
+    for step in &mut steps {
+        namespace_step(step, &rename);
+    }
+
+    match root.get_mut("steps") {
+        Some(Value::Sequence(root_steps)) => root_steps.extend(steps),
+        _ => {
+            root.insert(Value::from("steps"), Value::Sequence(steps));
+        }
+    }
+    Ok(())
+}
+
+/// Renames the step's id and its references to steps of the same file.
+fn namespace_step(step: &mut Value, rename: &dyn Fn(&str) -> Option<String>) {
+    let rename_field = |value: &mut Value| {
+        if let Some(id) = value.as_str().and_then(rename) {
+            *value = Value::String(id);
+        }
+    };
+    if let Some(id) = step.get_mut("id") {
+        rename_field(id);
+    }
+    if let Some(target) = step
+        .get_mut("observe")
+        .and_then(|observe| observe.get_mut("target"))
+    {
+        rename_field(target);
+    }
+    if let Some(producer) = step
+        .get_mut("stdin")
+        .and_then(|stdin| stdin.get_mut("step"))
+    {
+        rename_field(producer);
+    }
+    match step.get_mut("stdout_to") {
+        Some(Value::Mapping(pipe)) => {
+            if let Some(consumer) = pipe.get_mut("step") {
+                rename_field(consumer);
+            }
+        }
+        Some(consumer) => rename_field(consumer),
+        None => {}
+    }
+    if let Some(Value::Sequence(deps)) = step.get_mut("depends_on") {
+        for dep in deps {
+            if let Some(id) = dep.get_mut("id") {
+                rename_field(id);
+            }
+        }
+    }
+    rename_placeholders(step, rename);
+}
+
+fn rename_placeholders(value: &mut Value, rename: &dyn Fn(&str) -> Option<String>) {
+    match value {
+        Value::String(text) => *text = rename_step_refs(text, rename),
+        Value::Sequence(items) => items
+            .iter_mut()
+            .for_each(|item| rename_placeholders(item, rename)),
+        Value::Mapping(map) => map
+            .iter_mut()
+            .for_each(|(_, item)| rename_placeholders(item, rename)),
+        _ => {}
+    }
+}
+
+/// Splits `a.b.c=value` into its path and a YAML-parsed value.
+fn parse_override(item: &str) -> Result<(Vec<String>, Value), Box<dyn Error>> {
+    let (path, raw) = item
+        .split_once('=')
+        .ok_or_else(|| ConfigError(format!("--set '{}': expected PATH=VALUE", item)))?;
+    let path = path
+        .split('.')
+        .map(|part| part.trim().to_string())
+        .collect::<Vec<_>>();
+    if path.iter().any(String::is_empty) {
+        return Err(Box::new(ConfigError(format!(
+            "--set '{}': empty path segment",
+            item
+        ))));
+    }
+    let value = serde_yaml::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string()));
+    Ok((path, value))
+}
+
+/// Sets `path` in `root`. Under `steps` the next segments name a step id
+/// (ids may contain dots) or an index. `env` and `vars` entries, and fields
+/// that already hold a string, keep the raw text. `label` prefixes errors.
+pub(crate) fn set_path(
+    root: &mut Value,
+    path: &[String],
+    value: Value,
+    label: &str,
+) -> Result<(), Box<dyn Error>> {
+    let display = format!("{} {}", label, path.join("."));
+    let mut current = root;
+    let mut rest = path;
+    if rest[0] == "steps" {
+        let steps = match current.get_mut("steps") {
+            Some(Value::Sequence(steps)) => steps,
+            _ => {
+                return Err(Box::new(ConfigError(format!(
+                    "{}: config has no steps",
+                    display
+                ))));
+            }
+        };
+        let (index, used) = find_step(steps, &rest[1..]).ok_or_else(|| {
+            ConfigError(format!(
+                "{}: no step matches '{}'",
+                display,
+                rest[1..].join(".")
+            ))
+        })?;
+        current = &mut steps[index];
+        rest = &rest[1 + used..];
+        if rest.is_empty() {
+            return Err(Box::new(ConfigError(format!(
+                "{}: name a field of the step",
+                display
+            ))));
+        }
+    }
+
+    let (last, parents) = rest.split_last().unwrap_or((&rest[0], &[]));
+    for part in parents {
+        let map = current.as_mapping_mut().ok_or_else(|| {
+            ConfigError(format!("{}: '{}' is not inside a mapping", display, part))
+        })?;
+        current = map
+            .entry(Value::from(part.as_str()))
+            .or_insert_with(|| Value::Mapping(Mapping::new()));
+    }
+    let parent_is_text = matches!(parents.last().map(String::as_str), Some("env" | "vars"))
+        || (parents.is_empty() && path[0] == "vars");
+    let map = current
+        .as_mapping_mut()
+        .ok_or_else(|| ConfigError(format!("{}: '{}' is not inside a mapping", display, last)))?;
+    let keep_text = parent_is_text || matches!(map.get(last.as_str()), Some(Value::String(_)));
+    let value = match value {
+        Value::Number(number) if keep_text => Value::String(number.to_string()),
+        Value::Bool(flag) if keep_text => Value::String(flag.to_string()),
+        value => value,
+    };
+    map.insert(Value::from(last.as_str()), value);
+    Ok(())
+}
+
+/// Finds the step addressed by the leading `parts`: the longest matching
+/// dotted id, or a numeric index. Returns its index and the parts consumed.
+fn find_step(steps: &[Value], parts: &[String]) -> Option<(usize, usize)> {
+    let by_id = (1..=parts.len()).rev().find_map(|used| {
+        let id = parts[..used].join(".");
+        steps
+            .iter()
+            .position(|step| step.get("id").and_then(Value::as_str) == Some(id.as_str()))
+            .map(|index| (index, used))
+    });
+    by_id.or_else(|| {
+        let index = parts.first()?.parse::<usize>().ok()?;
+        (index < steps.len()).then_some((index, 1))
+    })
+}
+
+/// Resolves `vars`, `defaults` and `templates`/`extends` into plain steps.
+///
+/// Merge rules: mappings (`env`, `parallel`, `git`, ...) merge key by key with
+/// the later layer winning, a `null` value removes the inherited key, and
+/// everything else (scalars and lists such as `args`) is replaced as a whole.
+/// Layers apply in order: `defaults`, each template in `extends` order, the step.
+fn compose_config(mut root: Value) -> Result<Value, Box<dyn Error>> {
+    let root_map = root
+        .as_mapping_mut()
+        .ok_or_else(|| ConfigError("config must be a mapping".to_string()))?;
+
+    let defaults = match root_map.remove("defaults") {
+        Some(Value::Null) | None => Mapping::new(),
+        Some(Value::Mapping(defaults)) => defaults,
+        Some(_) => {
+            return Err(Box::new(ConfigError(
+                "defaults must be a mapping".to_string(),
+            )));
+        }
+    };
+    check_layer_keys(&defaults, "defaults")?;
+
+    let templates = match root_map.remove("templates") {
+        Some(Value::Null) | None => Mapping::new(),
+        Some(Value::Mapping(templates)) => templates,
+        Some(_) => {
+            return Err(Box::new(ConfigError(
+                "templates must be a mapping".to_string(),
+            )));
+        }
+    };
+
+    if let Some(vars) = root_map.get_mut("vars") {
+        *vars = stringify_vars(vars)?;
+    }
+    flatten_phases(root_map)?;
+
+    if let Some(Value::Sequence(steps)) = root_map.get_mut("steps") {
+        for step in steps.iter_mut() {
+            *step = compose_step(step, &defaults, &templates)?;
+        }
+    }
+    Ok(root)
+}
+
+/// Moves `phases[].steps` to the top-level `steps`, tagging each with its
+/// phase, so defaults, templates and `--set` treat them like any other step.
+fn flatten_phases(root: &mut Mapping) -> Result<(), Box<dyn Error>> {
+    let mut flattened = Vec::new();
+    match root.get_mut("phases") {
+        None | Some(Value::Null) => return Ok(()),
+        Some(Value::Sequence(phases)) => {
+            for phase in phases {
+                let phase = phase
+                    .as_mapping_mut()
+                    .ok_or_else(|| ConfigError("phases entries must be mappings".to_string()))?;
+                let name = phase
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| ConfigError("phases entries need a 'name'".to_string()))?
+                    .to_string();
+                let steps = match phase.remove("steps") {
+                    None | Some(Value::Null) => Vec::new(),
+                    Some(Value::Sequence(steps)) => steps,
+                    Some(_) => {
+                        return Err(Box::new(ConfigError(format!(
+                            "phase '{}': steps must be a list",
+                            name
+                        ))));
+                    }
+                };
+                for mut step in steps {
+                    if let Some(step) = step.as_mapping_mut() {
+                        step.insert(Value::from("phase"), Value::from(name.as_str()));
+                    }
+                    flattened.push(step);
+                }
+            }
+        }
+        Some(_) => return Err(Box::new(ConfigError("phases must be a list".to_string()))),
+    }
+    match root.get_mut("steps") {
+        Some(Value::Sequence(steps)) => steps.extend(flattened),
+        _ => {
+            root.insert(Value::from("steps"), Value::Sequence(flattened));
+        }
+    }
+    Ok(())
+}
+
+fn compose_step(
+    step: &Value,
+    defaults: &Mapping,
+    templates: &Mapping,
+) -> Result<Value, Box<dyn Error>> {
+    let step = match step {
+        Value::Mapping(step) => step,
+        _ => return Ok(step.clone()),
+    };
+    let label = step
+        .get("id")
+        .and_then(Value::as_str)
+        .unwrap_or("unknown")
+        .to_string();
+
+    let mut merged = Value::Mapping(defaults.clone());
+    for name in extends_list(step, &label)? {
+        let mut chain = Vec::new();
+        let template = resolve_template(&name, templates, &mut chain)?;
+        merge_value(&mut merged, template);
+    }
+    let mut own = step.clone();
+    own.remove("extends");
+    merge_value(&mut merged, Value::Mapping(own));
+    Ok(merged)
+}
+
+/// Returns a template with its own `extends` chain applied.
+fn resolve_template(
+    name: &str,
+    templates: &Mapping,
+    chain: &mut Vec<String>,
+) -> Result<Value, Box<dyn Error>> {
+    if chain.iter().any(|seen| seen == name) {
+        chain.push(name.to_string());
+        return Err(Box::new(ConfigError(format!(
+            "template cycle: {}",
+            chain.join(" -> ")
+        ))));
+    }
+    let template = match templates.get(name) {
+        Some(Value::Mapping(template)) => template,
+        Some(_) => {
+            return Err(Box::new(ConfigError(format!(
+                "template '{}' must be a mapping",
+                name
+            ))));
+        }
+        None => {
+            return Err(Box::new(ConfigError(format!(
+                "unknown template '{}'",
+                name
+            ))));
+        }
+    };
+    if template.contains_key("id") {
+        return Err(Box::new(ConfigError(format!(
+            "template '{}' must not set 'id'",
+            name
+        ))));
+    }
+
+    chain.push(name.to_string());
+    let mut merged = Value::Mapping(Mapping::new());
+    for parent in extends_list(template, &format!("template '{}'", name))? {
+        let parent = resolve_template(&parent, templates, chain)?;
+        merge_value(&mut merged, parent);
+    }
+    chain.pop();
+
+    let mut own = template.clone();
+    own.remove("extends");
+    merge_value(&mut merged, Value::Mapping(own));
+    Ok(merged)
+}
+
+/// Reads `extends` as one template name or a list of names.
+fn extends_list(layer: &Mapping, label: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    match layer.get("extends") {
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(Value::String(name)) => Ok(vec![name.clone()]),
+        Some(Value::Sequence(names)) => names
+            .iter()
+            .map(|name| {
+                name.as_str().map(str::to_string).ok_or_else(|| {
+                    Box::new(ConfigError(format!(
+                        "{}: extends must list template names",
+                        label
+                    ))) as _
+                })
+            })
+            .collect(),
+        Some(_) => Err(Box::new(ConfigError(format!(
+            "{}: extends must be a template name or a list of names",
+            label
+        )))),
+    }
+}
+
+fn check_layer_keys(layer: &Mapping, label: &str) -> Result<(), Box<dyn Error>> {
+    for key in STEP_ONLY_KEYS {
+        if layer.contains_key(*key) {
+            return Err(Box::new(ConfigError(format!(
+                "{} must not set '{}'",
+                label, key
+            ))));
+        }
+    }
+    Ok(())
+}
+
+/// Merges `overlay` into `base` following the rules on `compose_config`.
+fn merge_value(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                if value.is_null() {
+                    base.remove(&key);
+                    continue;
+                }
+                match base.get_mut(&key) {
+                    Some(existing) => merge_value(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Vars are used as placeholder text, so numbers and booleans become strings.
+fn stringify_vars(vars: &Value) -> Result<Value, Box<dyn Error>> {
+    let vars = match vars {
+        Value::Null => return Ok(Value::Mapping(Mapping::new())),
+        Value::Mapping(vars) => vars,
+        _ => return Err(Box::new(ConfigError("vars must be a mapping".to_string()))),
+    };
+    let mut strings = Mapping::new();
+    for (key, value) in vars {
+        let name = key
+            .as_str()
+            .ok_or_else(|| ConfigError("vars keys must be strings".to_string()))?;
+        let text = match value {
+            Value::String(text) => text.clone(),
+            Value::Number(number) => number.to_string(),
+            Value::Bool(flag) => flag.to_string(),
+            _ => {
+                return Err(Box::new(ConfigError(format!(
+                    "var '{}' must be a string, number or boolean",
+                    name
+                ))));
+            }
+        };
+        strings.insert(key.clone(), Value::String(text));
+    }
+    Ok(Value::Mapping(strings))
+}
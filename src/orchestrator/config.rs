@@ -1,37 +1,330 @@
+use crate::orchestrator::isolate::{NAMESPACES, namespaces};
+use crate::orchestrator::limits::{cpu_max_line, memory_line, pids_line};
+use crate::orchestrator::profile::PROFILES;
+use crate::orchestrator::rlimits::{RLIMITS, rlimit_resource, rlimit_value};
+use crate::orchestrator::sched::{cpu_list, ionice_class, sched_policy};
+use crate::orchestrator::templating::parse_duration_ms;
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
 
 #[derive(Debug, Deserialize, Clone)]
 pub(crate) struct Config {
+    /// Top-level `vars`, read by `{var:NAME}` placeholders.
+    #[serde(default)]
+    pub(crate) vars: HashMap<String, String>,
+    /// Ordered stages; their steps are tagged with `phase` when composed.
+    pub(crate) phases: Option<Vec<Phase>>,
+    /// Stop the remaining steps as soon as one step fails.
+    #[serde(default)]
+    pub(crate) fail_fast: bool,
     pub(crate) steps: Vec<Step>,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct Phase {
+    pub(crate) name: String,
+    /// Ends the phase early; otherwise it ends when its steps have exited.
+    /// A phase of only `persist` steps lasts exactly this long.
+    pub(crate) duration_ms: Option<Count>,
+}
+
+/// Top-level `trials`: repeated runs of the whole config.
+#[derive(Debug, Default, Deserialize, Clone)]
+pub(crate) struct Trials {
+    pub(crate) repeat: Option<u32>,
+    pub(crate) warmup: Option<u32>,
+    /// Pause before each trial after the first, e.g. `30s`.
+    pub(crate) cooldown: Option<Count>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub(crate) struct Step {
     pub(crate) id: Option<String>,
+    #[serde(default)]
     pub(crate) runtime: String,
     pub(crate) parallel: Option<Parallel>,
     pub(crate) location: Option<String>,
+    pub(crate) refresh: Option<String>,
+    pub(crate) git: Option<GitSource>,
+    pub(crate) entry: Option<String>,
+    pub(crate) source: Option<String>,
+    pub(crate) requirements: Option<Vec<String>>,
+    pub(crate) packages: Option<Vec<String>>,
     pub(crate) stdout: Option<bool>,
-    pub(crate) duration_ms: Option<u64>,
+    /// Where each process reads stdin from; nothing (`/dev/null`) when unset.
+    pub(crate) stdin: Option<StdinSource>,
+    /// Step whose stdin this step's stdout is piped into.
+    pub(crate) stdout_to: Option<StdoutTo>,
+    pub(crate) duration_ms: Option<Count>,
     pub(crate) env: Option<HashMap<String, String>>,
-    pub(crate) wrapper: Option<String>,
+    /// Starts processes without `wl`'s environment (only `env` and `WL_PROC_*`).
+    pub(crate) env_clear: Option<bool>,
+    /// Names kept from `wl`'s environment, `LC_*` matching a prefix; implies `env_clear`.
+    pub(crate) env_inherit: Option<Vec<String>>,
+    /// Working directory; defaults to `<run-dir>/scratch/<step-id>`.
+    pub(crate) cwd: Option<String>,
+    pub(crate) wrapper: Option<WrapperSpec>,
+    pub(crate) wrappers: Option<Vec<WrapperSpec>>,
+    pub(crate) profile: Option<String>,
+    pub(crate) observe: Option<Observe>,
     pub(crate) exec: Option<String>,
     pub(crate) args: Option<Vec<String>>,
     pub(crate) command: Option<String>,
     pub(crate) shell: Option<String>,
     pub(crate) depends_on: Option<Vec<Dependency>>,
+    /// Phase the step belongs to, set from `phases[].steps`.
+    pub(crate) phase: Option<String>,
+    /// Keeps a phase step running until the last phase ends.
+    pub(crate) persist: Option<bool>,
+    pub(crate) limits: Option<Limits>,
+    /// CPUs the step's processes may run on.
+    pub(crate) cpus: Option<CpuSpec>,
+    /// NUMA node whose CPUs and memory the step is bound to.
+    pub(crate) numa_node: Option<u32>,
+    pub(crate) nice: Option<i32>,
+    pub(crate) ionice: Option<Ionice>,
+    /// `other`, `batch`, `idle`, `fifo` or `rr`.
+    pub(crate) sched_policy: Option<String>,
+    /// Real-time priority (1-99) for `fifo` and `rr`.
+    pub(crate) sched_priority: Option<u32>,
+    /// POSIX limits set in each process before exec, keyed by `as`, `nofile`, ...
+    pub(crate) rlimits: Option<HashMap<String, LimitValue>>,
+    /// Written to `/proc/<pid>/oom_score_adj` (-1000 to 1000).
+    pub(crate) oom_score_adj: Option<i32>,
+    /// Namespaces each process runs in: `true` for all of them, or a list.
+    pub(crate) isolate: Option<IsolateSpec>,
+    /// User the workload runs as; wrappers and profilers keep `wl`'s.
+    pub(crate) user: Option<IdSpec>,
+    /// Group the workload runs as; defaults to the user's primary group.
+    pub(crate) group: Option<IdSpec>,
+    /// Drops supplementary groups instead of taking the user's.
+    pub(crate) clear_groups: Option<bool>,
+    /// Starts the workload without capabilities, even as root.
+    pub(crate) drop_caps: Option<bool>,
+    #[allow(dead_code)]
     pub(crate) when: Option<String>,
 }
 
+/// cgroup v2 limits shared by every process of a step.
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct Limits {
+    /// `cpu.max`: a CPU count (`0.5`, `150%`) or `"<quota> <period>"`.
+    pub(crate) cpu_max: Option<LimitValue>,
+    /// `memory.max` in bytes or with a K/M/G/T suffix.
+    pub(crate) memory_max: Option<LimitValue>,
+    pub(crate) memory_high: Option<LimitValue>,
+    /// `io.max` lines, e.g. `8:0 rbps=1048576 wiops=100`.
+    pub(crate) io_max: Option<Vec<String>>,
+    pub(crate) pids_max: Option<LimitValue>,
+    /// Parent cgroup; defaults to the cgroup `wl` runs in.
+    pub(crate) parent: Option<String>,
+    /// `fail` (default) or `rlimit` when the cgroup cannot be set up.
+    pub(crate) fallback: Option<String>,
+}
+
+/// A limit given as a number or as text such as `512M` or `max`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum LimitValue {
+    Number(f64),
+    Text(String),
+}
+
+/// A CPU set shared by all processes, or split between them with `spread`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum CpuSpec {
+    Set(CpuList),
+    Detailed(CpuOptions),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct CpuOptions {
+    pub(crate) set: CpuList,
+    /// Gives each process its own slice of `set`.
+    pub(crate) spread: Option<bool>,
+}
+
+/// CPUs as one index, a list such as `0-3,6`, or an array of indexes.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum CpuList {
+    Index(u32),
+    Line(String),
+    List(Vec<u32>),
+}
+
+/// Exactly one of a file, inline text, or another step's stdout.
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct StdinSource {
+    pub(crate) file: Option<String>,
+    pub(crate) text: Option<String>,
+    /// Id of the step whose stdout is piped in.
+    pub(crate) step: Option<String>,
+}
+
+/// `stdout_to` as a step id, or with fan-out and tee options.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum StdoutTo {
+    Step(String),
+    Detailed(StdoutPipe),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct StdoutPipe {
+    pub(crate) step: String,
+    /// `one` (default): the reading processes share one pipe; `all`: each
+    /// gets its own copy of the stream.
+    pub(crate) fan_out: Option<String>,
+    /// Log file that also receives the stream, relative to the run directory.
+    pub(crate) tee: Option<String>,
+}
+
+impl StdoutTo {
+    pub(crate) fn pipe(&self) -> StdoutPipe {
+        match self {
+            StdoutTo::Step(step) => StdoutPipe {
+                step: step.clone(),
+                fan_out: None,
+                tee: None,
+            },
+            StdoutTo::Detailed(pipe) => pipe.clone(),
+        }
+    }
+}
+
+/// A pipe from one step's stdout to another's stdin.
+pub(crate) struct PipeLink {
+    pub(crate) producer: String,
+    pub(crate) consumer: String,
+    pub(crate) fan_out_all: bool,
+    pub(crate) tee: Option<String>,
+}
+
+/// Links from `stdout_to` and `stdin.step`; naming the same pair from both
+/// ends gives one link.
+pub(crate) fn pipe_links(steps: &[Step]) -> Vec<PipeLink> {
+    let mut links = Vec::new();
+    for step in steps {
+        if let (Some(id), Some(to)) = (&step.id, &step.stdout_to) {
+            let pipe = to.pipe();
+            links.push(PipeLink {
+                producer: id.clone(),
+                consumer: pipe.step,
+                fan_out_all: pipe.fan_out.as_deref() == Some("all"),
+                tee: pipe.tee,
+            });
+        }
+    }
+    for step in steps {
+        let producer = step.stdin.as_ref().and_then(|stdin| stdin.step.as_ref());
+        if let (Some(id), Some(producer)) = (&step.id, producer)
+            && !links
+                .iter()
+                .any(|link| &link.producer == producer && &link.consumer == id)
+        {
+            links.push(PipeLink {
+                producer: producer.clone(),
+                consumer: id.clone(),
+                fan_out_all: false,
+                tee: None,
+            });
+        }
+    }
+    links
+}
+
+/// A user or group given by name or numeric id.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum IdSpec {
+    Id(u32),
+    Name(String),
+}
+
+/// `isolate: true` or a list of `pid`, `mount`, `net` and `uts`.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum IsolateSpec {
+    All(bool),
+    List(Vec<String>),
+}
+
+/// I/O scheduling class (`realtime`, `best-effort`, `idle`) and level 0-7.
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct Ionice {
+    pub(crate) class: String,
+    pub(crate) level: Option<u32>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub(crate) struct Parallel {
-    pub(crate) processes: Option<u32>,
+    pub(crate) processes: Option<Count>,
     pub(crate) threads: Option<u32>,
 }
 
+/// A count given as a number or as a `p"..."` placeholder resolved before the
+/// step starts.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum Count {
+    Value(u64),
+    Template(String),
+}
+
+impl Count {
+    /// The resolved value; `None` while still a template.
+    pub(crate) fn value(&self) -> Option<u64> {
+        match self {
+            Count::Value(value) => Some(*value),
+            Count::Template(_) => None,
+        }
+    }
+}
+
+/// A wrapper given as a shell-style string, an argv list, or with options.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum WrapperSpec {
+    Line(String),
+    Args(Vec<String>),
+    Detailed(WrapperOptions),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct WrapperOptions {
+    pub(crate) command: WrapperArgs,
+    pub(crate) append: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub(crate) enum WrapperArgs {
+    Line(String),
+    Args(Vec<String>),
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct GitSource {
+    pub(crate) repo: String,
+    #[serde(rename = "ref")]
+    pub(crate) git_ref: Option<String>,
+    pub(crate) dir: Option<String>,
+}
+
+/// Attaches `tool` to every PID of the `target` step until it ends.
+#[derive(Debug, Deserialize, Clone)]
+pub(crate) struct Observe {
+    pub(crate) target: String,
+    pub(crate) tool: String,
+    pub(crate) args: Option<Vec<String>>,
+}
+
+pub(crate) const OBSERVE_TOOLS: &[&str] = &["pidstat", "strace", "perf-record", "gdb"];
+
 #[derive(Debug, Deserialize, Clone)]
 pub(crate) struct Dependency {
     pub(crate) id: String,
@@ -55,39 +348,145 @@ pub(crate) fn validate_config(config: &Config) -> Result<(), Box<dyn Error>> {
         return Err(Box::new(ConfigError("steps must not be empty".to_string())));
     }
 
+    let ids = config
+        .steps
+        .iter()
+        .filter_map(|step| step.id.as_deref())
+        .collect::<Vec<_>>();
+    for (index, id) in ids.iter().enumerate() {
+        if ids[..index].contains(id) {
+            return Err(Box::new(ConfigError(format!("duplicate step id '{}'", id))));
+        }
+    }
+    validate_phases(config)?;
+    validate_pipes(config)?;
+
     for step in &config.steps {
+        if let Some(observe) = &step.observe {
+            validate_observe(step, observe, &ids)?;
+            continue;
+        }
+
         let runtime = step.runtime.trim().to_lowercase();
         if runtime.is_empty() {
             return Err(Box::new(ConfigError("runtime must be set".to_string())));
         }
 
         if let Some(parallel) = &step.parallel {
-            if let Some(processes) = parallel.processes {
-                if processes == 0 {
-                    return Err(Box::new(ConfigError(
-                        "parallel.processes must be > 0".to_string(),
-                    )));
-                }
+            if matches!(parallel.processes, Some(Count::Value(0))) {
+                return Err(Box::new(ConfigError(
+                    "parallel.processes must be > 0".to_string(),
+                )));
             }
-            if let Some(threads) = parallel.threads {
-                if threads == 0 {
-                    return Err(Box::new(ConfigError(
-                        "parallel.threads must be > 0".to_string(),
-                    )));
-                }
+            if parallel.threads == Some(0) {
+                return Err(Box::new(ConfigError(
+                    "parallel.threads must be > 0".to_string(),
+                )));
+            }
+        }
+
+        if matches!(step.duration_ms, Some(Count::Value(0))) {
+            return Err(Box::new(ConfigError("duration_ms must be > 0".to_string())));
+        }
+
+        if step.wrapper.is_some() && step.wrappers.is_some() {
+            return Err(Box::new(ConfigError(
+                "'wrapper' and 'wrappers' cannot be used together".to_string(),
+            )));
+        }
+
+        if let Some(profile) = &step.profile
+            && !PROFILES.contains(&profile.as_str())
+        {
+            return Err(Box::new(ConfigError(format!(
+                "profile must be one of {} (got '{}')",
+                PROFILES.join(", "),
+                profile
+            ))));
+        }
+
+        if let Some(refresh) = &step.refresh {
+            validate_refresh(refresh)?;
+        }
+
+        if let Some(limits) = &step.limits {
+            validate_limits(limits)?;
+        }
+        validate_sched(step)?;
+        validate_rlimits(step)?;
+        if let Some(stdin) = &step.stdin {
+            validate_stdin(step, stdin)?;
+        }
+        if let Some(to) = &step.stdout_to {
+            validate_stdout_to(step, to)?;
+        }
+        if let Some(isolate) = &step.isolate
+            && namespaces(isolate).is_none()
+        {
+            let names = NAMESPACES.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+            return Err(Box::new(ConfigError(format!(
+                "isolate must be true, false or a list of {}",
+                names.join(", ")
+            ))));
+        }
+
+        if let Some(git) = &step.git {
+            if git.repo.trim().is_empty() {
+                return Err(Box::new(ConfigError("git.repo must be set".to_string())));
+            }
+            if step.location.is_some() {
+                return Err(Box::new(ConfigError(
+                    "'git' and 'location' cannot be used together".to_string(),
+                )));
+            }
+            if runtime == "bin" || runtime == "shell" {
+                return Err(Box::new(ConfigError(format!(
+                    "{} runtime does not support 'git' sources",
+                    runtime
+                ))));
+            }
+            if step.entry.is_none() && runtime != "golang" && runtime != "go" {
+                return Err(Box::new(ConfigError(
+                    "'git' sources require 'entry' for this runtime".to_string(),
+                )));
             }
         }
 
-        if let Some(duration) = step.duration_ms {
-            if duration == 0 {
+        if step.source.is_some() {
+            if step.location.is_some() || step.git.is_some() {
                 return Err(Box::new(ConfigError(
-                    "duration_ms must be > 0".to_string(),
+                    "'source' cannot be combined with 'location' or 'git'".to_string(),
                 )));
             }
+            if runtime == "bin" || runtime == "shell" {
+                return Err(Box::new(ConfigError(format!(
+                    "{} runtime does not support inline 'source'",
+                    runtime
+                ))));
+            }
+        }
+
+        if step.requirements.is_some()
+            && !matches!(runtime.as_str(), "python" | "python3" | "cpython")
+        {
+            return Err(Box::new(ConfigError(
+                "'requirements' is only supported by the python runtime".to_string(),
+            )));
+        }
+        if step.packages.is_some() && !matches!(runtime.as_str(), "node" | "node.js") {
+            return Err(Box::new(ConfigError(
+                "'packages' is only supported by the node runtime".to_string(),
+            )));
         }
 
         if runtime == "bin" {
-            if step.exec.as_deref().unwrap_or("").is_empty() {
+            if step.location.is_some() {
+                if step.entry.is_none() {
+                    return Err(Box::new(ConfigError(
+                        "bin runtime with 'location' requires 'entry'".to_string(),
+                    )));
+                }
+            } else if step.exec.as_deref().unwrap_or("").is_empty() {
                 return Err(Box::new(ConfigError(
                     "bin runtime requires 'exec'".to_string(),
                 )));
@@ -114,12 +513,13 @@ pub(crate) fn validate_config(config: &Config) -> Result<(), Box<dyn Error>> {
                         "depends_on.id must be set".to_string(),
                     )));
                 }
-                if let Some(when) = &dep.when {
-                    if when != "started" && when != "exited" {
-                        return Err(Box::new(ConfigError(
-                            "depends_on.when must be 'started' or 'exited'".to_string(),
-                        )));
-                    }
+                if let Some(when) = &dep.when
+                    && when != "started"
+                    && when != "exited"
+                {
+                    return Err(Box::new(ConfigError(
+                        "depends_on.when must be 'started' or 'exited'".to_string(),
+                    )));
                 }
                 if dep.exit_codes.is_some() && dep.when.as_deref() != Some("exited") {
                     return Err(Box::new(ConfigError(
@@ -133,10 +533,402 @@ pub(crate) fn validate_config(config: &Config) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+fn validate_observe(step: &Step, observe: &Observe, ids: &[&str]) -> Result<(), Box<dyn Error>> {
+    if !step.runtime.trim().is_empty() {
+        return Err(Box::new(ConfigError(
+            "observe steps must not set 'runtime'".to_string(),
+        )));
+    }
+    if !ids.contains(&observe.target.as_str()) {
+        return Err(Box::new(ConfigError(format!(
+            "observe.target '{}' does not match any step id",
+            observe.target
+        ))));
+    }
+    if step.id.as_deref() == Some(observe.target.as_str()) {
+        return Err(Box::new(ConfigError(
+            "observe.target must not be the observer itself".to_string(),
+        )));
+    }
+    if !OBSERVE_TOOLS.contains(&observe.tool.as_str()) {
+        return Err(Box::new(ConfigError(format!(
+            "observe.tool must be one of {} (got '{}')",
+            OBSERVE_TOOLS.join(", "),
+            observe.tool
+        ))));
+    }
+    if step.duration_ms.is_some() || step.parallel.is_some() {
+        return Err(Box::new(ConfigError(
+            "observe steps end with their target; 'duration_ms' and 'parallel' are not allowed"
+                .to_string(),
+        )));
+    }
+    let unsupported = [
+        ("cwd", step.cwd.is_some()),
+        ("stdin", step.stdin.is_some()),
+        ("stdout_to", step.stdout_to.is_some()),
+        ("env_clear", step.env_clear.is_some()),
+        ("env_inherit", step.env_inherit.is_some()),
+        ("limits", step.limits.is_some()),
+        ("rlimits", step.rlimits.is_some()),
+        ("oom_score_adj", step.oom_score_adj.is_some()),
+        ("isolate", step.isolate.is_some()),
+        ("user", step.user.is_some()),
+        ("group", step.group.is_some()),
+        ("clear_groups", step.clear_groups.is_some()),
+        ("drop_caps", step.drop_caps.is_some()),
+        ("cpus", step.cpus.is_some()),
+        ("numa_node", step.numa_node.is_some()),
+        ("nice", step.nice.is_some()),
+        ("ionice", step.ionice.is_some()),
+        ("sched_policy", step.sched_policy.is_some()),
+    ];
+    if let Some((field, _)) = unsupported.iter().find(|(_, set)| *set) {
+        return Err(Box::new(ConfigError(format!(
+            "observe steps do not support '{}'",
+            field
+        ))));
+    }
+    Ok(())
+}
+
+fn validate_stdin(step: &Step, stdin: &StdinSource) -> Result<(), Box<dyn Error>> {
+    let sources = [&stdin.file, &stdin.text, &stdin.step];
+    if sources.iter().filter(|source| source.is_some()).count() != 1 {
+        return Err(Box::new(ConfigError(
+            "stdin must set exactly one of 'file', 'text' or 'step'".to_string(),
+        )));
+    }
+    if stdin.step.is_some() && step.id.is_none() {
+        return Err(Box::new(ConfigError(
+            "step id is required when using stdin.step".to_string(),
+        )));
+    }
+    Ok(())
+}
+
+fn validate_stdout_to(step: &Step, to: &StdoutTo) -> Result<(), Box<dyn Error>> {
+    if step.id.is_none() {
+        return Err(Box::new(ConfigError(
+            "step id is required when using stdout_to".to_string(),
+        )));
+    }
+    let pipe = to.pipe();
+    if !matches!(pipe.fan_out.as_deref(), None | Some("one") | Some("all")) {
+        return Err(Box::new(ConfigError(
+            "stdout_to.fan_out must be 'one' or 'all'".to_string(),
+        )));
+    }
+    Ok(())
+}
+
+/// Checks the links made by `stdout_to` and `stdin.step` as a whole: each
+/// joins two distinct workload steps, and a step writes to and reads from at
+/// most one pipe.
+fn validate_pipes(config: &Config) -> Result<(), Box<dyn Error>> {
+    let links = pipe_links(&config.steps);
+    for (index, link) in links.iter().enumerate() {
+        if link.producer == link.consumer {
+            return Err(Box::new(ConfigError(format!(
+                "step '{}' cannot pipe its stdout into its own stdin",
+                link.producer
+            ))));
+        }
+        for id in [&link.producer, &link.consumer] {
+            let step = config
+                .steps
+                .iter()
+                .find(|step| step.id.as_ref() == Some(id))
+                .ok_or_else(|| {
+                    ConfigError(format!("pipe step '{}' does not match any step id", id))
+                })?;
+            if step.observe.is_some() {
+                return Err(Box::new(ConfigError(format!(
+                    "observe step '{}' cannot be piped",
+                    id
+                ))));
+            }
+            if id == &link.producer && step.stdout.unwrap_or(false) {
+                return Err(Box::new(ConfigError(format!(
+                    "step '{}' pipes its stdout to '{}' and cannot also set 'stdout: true'",
+                    id, link.consumer
+                ))));
+            }
+            if id == &link.consumer
+                && step
+                    .stdin
+                    .as_ref()
+                    .is_some_and(|stdin| stdin.step.is_none())
+            {
+                return Err(Box::new(ConfigError(format!(
+                    "step '{}' reads stdin from '{}' and cannot also set stdin.file or stdin.text",
+                    id, link.producer
+                ))));
+            }
+        }
+        if waits_for_exit(&config.steps, &link.consumer, &link.producer) {
+            return Err(Box::new(ConfigError(format!(
+                "step '{}' cannot wait for its stdin writer '{}' to exit (the pipe would fill up)",
+                link.consumer, link.producer
+            ))));
+        }
+        if waits_for_exit(&config.steps, &link.producer, &link.consumer) {
+            return Err(Box::new(ConfigError(format!(
+                "step '{}' cannot wait for its stdout reader '{}' to exit (the reader would never see EOF)",
+                link.producer, link.consumer
+            ))));
+        }
+        let earlier = &links[..index];
+        if earlier.iter().any(|other| other.producer == link.producer) {
+            return Err(Box::new(ConfigError(format!(
+                "step '{}' pipes its stdout to more than one step",
+                link.producer
+            ))));
+        }
+        if earlier.iter().any(|other| other.consumer == link.consumer) {
+            return Err(Box::new(ConfigError(format!(
+                "step '{}' reads stdin from more than one step",
+                link.consumer
+            ))));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `from` cannot start until `target` has exited, directly or
+/// through other steps' `depends_on`.
+fn waits_for_exit(steps: &[Step], from: &str, target: &str) -> bool {
+    let mut pending = vec![from];
+    let mut seen = HashSet::new();
+    while let Some(id) = pending.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        let Some(step) = steps.iter().find(|step| step.id.as_deref() == Some(id)) else {
+            continue;
+        };
+        for dep in step.depends_on.iter().flatten() {
+            if dep.id == target && dep.when.as_deref() == Some("exited") {
+                return true;
+            }
+            pending.push(&dep.id);
+        }
+    }
+    false
+}
+
+fn validate_sched(step: &Step) -> Result<(), Box<dyn Error>> {
+    if let Some(cpus) = &step.cpus {
+        let set = match cpus {
+            CpuSpec::Set(set) => set,
+            CpuSpec::Detailed(options) => &options.set,
+        };
+        if cpu_list(set).is_none() {
+            return Err(Box::new(ConfigError(
+                "cpus must be a CPU index, a list such as '0-3,6' or an array of indexes"
+                    .to_string(),
+            )));
+        }
+    }
+    if step.numa_node.is_some_and(|node| node >= u64::BITS) {
+        return Err(Box::new(ConfigError(format!(
+            "numa_node must be below {}",
+            u64::BITS
+        ))));
+    }
+    if let Some(nice) = step.nice
+        && !(-20..=19).contains(&nice)
+    {
+        return Err(Box::new(ConfigError(format!(
+            "nice must be between -20 and 19 (got {})",
+            nice
+        ))));
+    }
+    if let Some(ionice) = &step.ionice {
+        if ionice_class(&ionice.class).is_none() {
+            return Err(Box::new(ConfigError(format!(
+                "ionice.class must be 'realtime', 'best-effort' or 'idle' (got '{}')",
+                ionice.class
+            ))));
+        }
+        if ionice.level.is_some_and(|level| level > 7) {
+            return Err(Box::new(ConfigError(
+                "ionice.level must be between 0 and 7".to_string(),
+            )));
+        }
+    }
+    let policy = match &step.sched_policy {
+        Some(name) => sched_policy(name).ok_or_else(|| {
+            ConfigError(format!(
+                "sched_policy must be one of other, batch, idle, fifo, rr (got '{}')",
+                name
+            ))
+        })?,
+        None => libc::SCHED_OTHER,
+    };
+    let realtime = policy == libc::SCHED_FIFO || policy == libc::SCHED_RR;
+    match step.sched_priority {
+        Some(priority) if realtime && !(1..=99).contains(&priority) => Err(Box::new(ConfigError(
+            "sched_priority must be between 1 and 99".to_string(),
+        ))),
+        Some(_) if !realtime => Err(Box::new(ConfigError(
+            "sched_priority requires sched_policy fifo or rr".to_string(),
+        ))),
+        None if realtime => Err(Box::new(ConfigError(
+            "sched_policy fifo and rr require sched_priority".to_string(),
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn validate_rlimits(step: &Step) -> Result<(), Box<dyn Error>> {
+    for (name, value) in step.rlimits.iter().flatten() {
+        if rlimit_resource(name).is_none() {
+            let names = RLIMITS.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+            return Err(Box::new(ConfigError(format!(
+                "rlimits keys must be one of {} (got '{}')",
+                names.join(", "),
+                name
+            ))));
+        }
+        if rlimit_value(name, value).is_none() {
+            return Err(Box::new(ConfigError(format!(
+                "rlimits.{} must be {} or 'unlimited'",
+                name,
+                match name.as_str() {
+                    "cpu" => "a number of seconds or a duration such as 2m",
+                    "nofile" | "nproc" => "a count",
+                    _ => "a size such as 512M",
+                }
+            ))));
+        }
+    }
+    if let Some(adj) = step.oom_score_adj
+        && !(-1000..=1000).contains(&adj)
+    {
+        return Err(Box::new(ConfigError(format!(
+            "oom_score_adj must be between -1000 and 1000 (got {})",
+            adj
+        ))));
+    }
+    Ok(())
+}
+
+fn validate_limits(limits: &Limits) -> Result<(), Box<dyn Error>> {
+    if let Some(cpu) = &limits.cpu_max
+        && cpu_max_line(cpu).is_none()
+    {
+        return Err(Box::new(ConfigError(
+            "limits.cpu_max must be a CPU count, a percentage or '<quota> <period>'".to_string(),
+        )));
+    }
+    for (name, value) in [
+        ("memory_max", &limits.memory_max),
+        ("memory_high", &limits.memory_high),
+    ] {
+        if let Some(value) = value
+            && memory_line(value).is_none()
+        {
+            return Err(Box::new(ConfigError(format!(
+                "limits.{} must be a size such as 512M or 'max'",
+                name
+            ))));
+        }
+    }
+    if let Some(pids) = &limits.pids_max
+        && pids_line(pids).is_none()
+    {
+        return Err(Box::new(ConfigError(
+            "limits.pids_max must be a count > 0 or 'max'".to_string(),
+        )));
+    }
+    for line in limits.io_max.iter().flatten() {
+        let mut fields = line.split_whitespace();
+        let device = fields.next().unwrap_or("");
+        if !device.contains(':') || fields.next().is_none() {
+            return Err(Box::new(ConfigError(format!(
+                "limits.io_max entries must look like '8:0 rbps=1048576' (got '{}')",
+                line
+            ))));
+        }
+    }
+    if let Some(fallback) = &limits.fallback
+        && fallback != "fail"
+        && fallback != "rlimit"
+    {
+        return Err(Box::new(ConfigError(format!(
+            "limits.fallback must be 'fail' or 'rlimit' (got '{}')",
+            fallback
+        ))));
+    }
+    Ok(())
+}
+
+fn validate_phases(config: &Config) -> Result<(), Box<dyn Error>> {
+    let phases = config.phases.as_deref().unwrap_or(&[]);
+    for (index, phase) in phases.iter().enumerate() {
+        if phase.name.trim().is_empty() {
+            return Err(Box::new(ConfigError("phase name must be set".to_string())));
+        }
+        if phases[..index].iter().any(|other| other.name == phase.name) {
+            return Err(Box::new(ConfigError(format!(
+                "duplicate phase '{}'",
+                phase.name
+            ))));
+        }
+        if let Some(duration) = &phase.duration_ms
+            && phase_duration_ms(duration).is_none()
+        {
+            return Err(Box::new(ConfigError(format!(
+                "phase '{}': duration_ms must be a duration > 0",
+                phase.name
+            ))));
+        }
+    }
+    for step in &config.steps {
+        if !phases.is_empty() && step.id.is_none() {
+            return Err(Box::new(ConfigError(
+                "step id is required when using phases".to_string(),
+            )));
+        }
+        if let Some(name) = &step.phase
+            && !phases.iter().any(|phase| &phase.name == name)
+        {
+            return Err(Box::new(ConfigError(format!(
+                "step '{}': unknown phase '{}'",
+                step.id.as_deref().unwrap_or("unknown"),
+                name
+            ))));
+        }
+    }
+    Ok(())
+}
+
+/// A phase duration in ms: a number or a string such as `30s`.
+pub(crate) fn phase_duration_ms(duration: &Count) -> Option<u64> {
+    let ms = match duration {
+        Count::Value(value) => Some(*value),
+        Count::Template(text) => parse_duration_ms(text.trim()),
+    };
+    ms.filter(|ms| *ms > 0)
+}
+
+pub(crate) fn validate_refresh(value: &str) -> Result<(), Box<dyn Error>> {
+    match value {
+        "never" | "always" | "conditional" => Ok(()),
+        _ => Err(Box::new(ConfigError(format!(
+            "refresh must be 'never', 'always' or 'conditional' (got '{}')",
+            value
+        )))),
+    }
+}
+
 pub(crate) fn step_processes(step: &Step) -> u32 {
     step.parallel
         .as_ref()
-        .and_then(|parallel| parallel.processes)
+        .and_then(|parallel| parallel.processes.as_ref())
+        .and_then(Count::value)
+        .map(|processes| u32::try_from(processes).unwrap_or(u32::MAX))
         .unwrap_or(1)
 }
 
@@ -145,7 +937,16 @@ pub(crate) fn step_stdout(step: &Step) -> bool {
 }
 
 pub(crate) fn step_duration_ms(step: &Step) -> Option<u64> {
-    step.duration_ms
+    step.duration_ms.as_ref().and_then(Count::value)
+}
+
+/// Wrappers in composition order; the first one is the outermost command.
+pub(crate) fn step_wrappers(step: &Step) -> Vec<&WrapperSpec> {
+    match (&step.wrapper, &step.wrappers) {
+        (Some(wrapper), _) => vec![wrapper],
+        (None, Some(wrappers)) => wrappers.iter().collect(),
+        (None, None) => Vec::new(),
+    }
 }
 
 pub(crate) fn step_env(step: &Step) -> Vec<(String, String)> {
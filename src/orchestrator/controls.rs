@@ -0,0 +1,171 @@
+use crate::orchestrator::cache::CacheContext;
+use crate::orchestrator::config::{ConfigError, Step, step_processes};
+use crate::orchestrator::credentials::credentials;
+use crate::orchestrator::isolate::{Isolation, isolation};
+use crate::orchestrator::limits::{AppliedLimits, CgroupReport, apply_limits};
+use crate::orchestrator::pipes::Pipes;
+use crate::orchestrator::process::SpawnSettings;
+use crate::orchestrator::rlimits::{ProcessLimits, process_limits};
+use crate::orchestrator::sched::{Scheduling, scheduling};
+use crate::orchestrator::templating::{ProcessVars, expand_process_tokens};
+use std::error::Error;
+use std::fs::File;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+/// What a runtime applies to every process of one step at spawn time.
+pub(crate) struct StepControls {
+    step_id: String,
+    stdin: Input,
+    pipes: Arc<Pipes>,
+    /// Working directory, possibly with a deferred `{proc_index}`.
+    cwd: String,
+    /// Names kept from `wl`'s environment; `None` inherits all of it.
+    env_inherit: Option<Vec<String>>,
+    processes: u32,
+    /// Owner given to a `cwd` that `wl` creates, when the workload changes user.
+    owner: Option<(Option<u32>, Option<u32>)>,
+    limits: Option<AppliedLimits>,
+    scheduling: Option<Scheduling>,
+    rlimits: Option<ProcessLimits>,
+    isolation: Option<Isolation>,
+}
+
+/// Where each process's stdin comes from.
+enum Input {
+    Null,
+    /// A file opened per process, possibly with a deferred `{proc_index}`.
+    File(String),
+    /// The pipe from another step.
+    Pipe,
+}
+
+impl StepControls {
+    /// Sets up the step's controls, creating its cgroup when it has `limits`.
+    pub(crate) fn new(
+        step: &Step,
+        cache: &CacheContext,
+        pipes: Arc<Pipes>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let step_id = step.id.as_deref().unwrap_or("unknown");
+        let cwd = match &step.cwd {
+            Some(cwd) => std::path::absolute(cwd)?,
+            None => cache.run_dir.join("scratch").join(step_id),
+        };
+        let env_inherit = match (&step.env_inherit, step.env_clear) {
+            (Some(names), _) => Some(names.clone()),
+            (None, Some(true)) => Some(Vec::new()),
+            (None, _) => None,
+        };
+        let stdin = match &step.stdin {
+            _ if pipes.reads(step_id) => Input::Pipe,
+            Some(stdin) => match (&stdin.file, &stdin.text) {
+                (Some(file), _) => {
+                    Input::File(std::path::absolute(file)?.to_string_lossy().to_string())
+                }
+                (None, Some(text)) => {
+                    let path = cache.run_dir.join("stdin").join(format!("{}.txt", step_id));
+                    std::fs::create_dir_all(cache.run_dir.join("stdin"))?;
+                    std::fs::write(&path, text)?;
+                    Input::File(path.to_string_lossy().to_string())
+                }
+                (None, None) => Input::Null,
+            },
+            None => Input::Null,
+        };
+        let limits = match &step.limits {
+            Some(limits) => Some(apply_limits(limits, &cache.run_id, step_id)?),
+            None => None,
+        };
+        Ok(StepControls {
+            step_id: step_id.to_string(),
+            stdin,
+            pipes,
+            cwd: cwd.to_string_lossy().to_string(),
+            env_inherit,
+            processes: step_processes(step),
+            owner: credentials(step)?.and_then(|credentials| credentials.owner()),
+            limits,
+            scheduling: scheduling(step)?,
+            rlimits: process_limits(step),
+            isolation: isolation(step, &cwd, &cache.base_dir)?,
+        })
+    }
+
+    /// Configures one process before it is spawned. Call it before adding the
+    /// step's `env`, which an `env_clear` here would otherwise drop.
+    pub(crate) fn prepare(
+        &self,
+        command: &mut Command,
+        vars: &ProcessVars,
+    ) -> Result<SpawnSettings, Box<dyn Error>> {
+        let cwd = PathBuf::from(expand_process_tokens(&self.cwd, vars));
+        if !cwd.exists() {
+            std::fs::create_dir_all(&cwd)?;
+            if let Some((uid, gid)) = self.owner {
+                std::os::unix::fs::chown(&cwd, uid, gid)?;
+            }
+        }
+        command.current_dir(&cwd);
+        if let Some(names) = &self.env_inherit {
+            command.env_clear();
+            for (key, value) in std::env::vars_os() {
+                let key_text = key.to_string_lossy();
+                if names.iter().any(|name| env_name_matches(name, &key_text)) {
+                    command.env(&key, value);
+                }
+            }
+        }
+        match &self.stdin {
+            Input::Null => {
+                command.stdin(Stdio::null());
+            }
+            Input::File(path) => {
+                let path = expand_process_tokens(path, vars);
+                let file = File::open(&path)
+                    .map_err(|err| ConfigError(format!("stdin file '{}': {}", path, err)))?;
+                command.stdin(file);
+            }
+            Input::Pipe => {
+                let read = self.pipes.stdin(&self.step_id)?;
+                command.stdin(read.map_or_else(Stdio::null, Stdio::from));
+            }
+        }
+        if let Some(write) = self.pipes.stdout(&self.step_id)? {
+            command.stdout(write);
+        }
+        command.env("WL_PROC_INDEX", vars.proc_index.to_string());
+        command.env("WL_PROC_COUNT", self.processes.to_string());
+
+        let mut settings = SpawnSettings::default();
+        if let Some(limits) = &self.limits {
+            limits.prepare(command, &mut settings);
+        }
+        if let Some(scheduling) = &self.scheduling {
+            settings
+                .fields
+                .push_str(&scheduling.prepare(command, vars.proc_index));
+        }
+        if let Some(rlimits) = &self.rlimits {
+            rlimits.prepare(command, &mut settings);
+        }
+        if let Some(isolation) = &self.isolation {
+            isolation.prepare(command, &mut settings)?;
+        }
+        Ok(settings)
+    }
+
+    /// Releases per-step resources once every process has been reaped.
+    pub(crate) fn finish(self, step_id: &str) -> Option<CgroupReport> {
+        self.limits.and_then(|limits| limits.finish(step_id))
+    }
+}
+
+/// `NAME` matches exactly; `PREFIX*` matches every name starting with `PREFIX`.
+fn env_name_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
@@ -0,0 +1,168 @@
+use crate::orchestrator::config::{
+    ConfigError, IdSpec, Step, WrapperArgs, WrapperOptions, WrapperSpec,
+};
+use crate::orchestrator::isolate::namespaces;
+use std::error::Error;
+use std::ffi::CString;
+use std::io;
+
+/// Buffer size for `getpwnam_r` and friends; doubled on ERANGE.
+const LOOKUP_BUFFER: usize = 4096;
+
+/// Who the workload runs as and what it keeps. `setpriv` applies it as the
+/// innermost wrapper, so wrappers and profilers keep `wl`'s privileges.
+pub(crate) struct Credentials {
+    uid: Option<u32>,
+    gid: Option<u32>,
+    /// Whether the user has a passwd entry to take supplementary groups from.
+    init_groups: bool,
+    clear_groups: bool,
+    drop_caps: bool,
+    /// Emptying the bounding set needs CAP_SETPCAP: root, or root of the
+    /// step's own user namespace.
+    bounding_set: bool,
+}
+
+/// Resolves the step's `user`, `group`, `clear_groups` and `drop_caps`;
+/// `None` when it sets none of them.
+pub(crate) fn credentials(step: &Step) -> Result<Option<Credentials>, Box<dyn Error>> {
+    let clear_groups = step.clear_groups.unwrap_or(false);
+    let drop_caps = step.drop_caps.unwrap_or(false);
+    if step.user.is_none() && step.group.is_none() && !clear_groups && !drop_caps {
+        return Ok(None);
+    }
+    // SAFETY: geteuid cannot fail.
+    let root = unsafe { libc::geteuid() } == 0;
+    if !root && (step.user.is_some() || step.group.is_some() || clear_groups) {
+        return Err(Box::new(ConfigError(
+            "'user', 'group' and 'clear_groups' need wl to run as root".to_string(),
+        )));
+    }
+
+    let user = step.user.as_ref().map(lookup_user).transpose()?;
+    let gid = match (&step.group, &user) {
+        (Some(group), _) => Some(lookup_group(group)?),
+        (None, Some((_, Some(gid)))) => Some(*gid),
+        (None, Some((uid, None))) => {
+            return Err(Box::new(ConfigError(format!(
+                "user {} has no passwd entry; set 'group' as well",
+                uid
+            ))));
+        }
+        (None, None) => None,
+    };
+    let isolated = step
+        .isolate
+        .as_ref()
+        .and_then(namespaces)
+        .is_some_and(|names| !names.is_empty());
+    Ok(Some(Credentials {
+        uid: user.map(|(uid, _)| uid),
+        gid,
+        init_groups: user.is_some_and(|(_, gid)| gid.is_some()),
+        clear_groups,
+        drop_caps,
+        bounding_set: root || isolated,
+    }))
+}
+
+impl Credentials {
+    /// Owner for directories `wl` creates for the workload, when it changes.
+    pub(crate) fn owner(&self) -> Option<(Option<u32>, Option<u32>)> {
+        (self.uid.is_some() || self.gid.is_some()).then_some((self.uid, self.gid))
+    }
+
+    fn setpriv_args(&self) -> Vec<String> {
+        let mut args = vec!["setpriv".to_string()];
+        if let Some(uid) = self.uid {
+            args.push(format!("--reuid={}", uid));
+        }
+        if let Some(gid) = self.gid {
+            args.push(format!("--regid={}", gid));
+        }
+        if self.clear_groups {
+            args.push("--clear-groups".to_string());
+        } else if self.init_groups {
+            args.push("--init-groups".to_string());
+        } else if self.uid.is_some() || self.gid.is_some() {
+            args.push("--keep-groups".to_string());
+        }
+        if self.drop_caps {
+            args.push("--inh-caps=-all".to_string());
+            args.push("--ambient-caps=-all".to_string());
+            if self.bounding_set {
+                args.push("--bounding-set=-all".to_string());
+            }
+        }
+        args
+    }
+}
+
+/// The `setpriv` wrapper for the step's credentials, if it sets any.
+pub(crate) fn setpriv_wrapper(step: &Step) -> Result<Option<WrapperSpec>, Box<dyn Error>> {
+    Ok(credentials(step)?.map(|credentials| {
+        WrapperSpec::Detailed(WrapperOptions {
+            command: WrapperArgs::Args(credentials.setpriv_args()),
+            append: None,
+        })
+    }))
+}
+
+/// A user's uid and, when it has a passwd entry, its primary gid.
+fn lookup_user(spec: &IdSpec) -> Result<(u32, Option<u32>), Box<dyn Error>> {
+    let entry = match spec {
+        IdSpec::Id(uid) => lookup(|entry, buf, len, result| {
+            // SAFETY: every pointer refers to a live buffer of the given size.
+            unsafe { libc::getpwuid_r(*uid, entry, buf, len, result) }
+        })?,
+        IdSpec::Name(name) => {
+            let name = CString::new(name.as_str())?;
+            lookup(|entry, buf, len, result| {
+                // SAFETY: every pointer refers to a live buffer of the given size.
+                unsafe { libc::getpwnam_r(name.as_ptr(), entry, buf, len, result) }
+            })?
+        }
+    };
+    match (spec, entry) {
+        (_, Some(entry)) => Ok((entry.pw_uid, Some(entry.pw_gid))),
+        (IdSpec::Id(uid), None) => Ok((*uid, None)),
+        (IdSpec::Name(name), None) => {
+            Err(Box::new(ConfigError(format!("user '{}' not found", name))))
+        }
+    }
+}
+
+fn lookup_group(spec: &IdSpec) -> Result<u32, Box<dyn Error>> {
+    let name = match spec {
+        IdSpec::Id(gid) => return Ok(*gid),
+        IdSpec::Name(name) => name,
+    };
+    let c_name = CString::new(name.as_str())?;
+    let entry: Option<libc::group> = lookup(|entry, buf, len, result| {
+        // SAFETY: every pointer refers to a live buffer of the given size.
+        unsafe { libc::getgrnam_r(c_name.as_ptr(), entry, buf, len, result) }
+    })?;
+    entry
+        .map(|entry| entry.gr_gid)
+        .ok_or_else(|| Box::new(ConfigError(format!("group '{}' not found", name))) as _)
+}
+
+/// Runs a reentrant passwd/group lookup, growing the buffer as needed. Only
+/// the numeric fields of the returned entry are valid afterwards.
+fn lookup<T>(
+    call: impl Fn(*mut T, *mut libc::c_char, usize, *mut *mut T) -> libc::c_int,
+) -> io::Result<Option<T>> {
+    let mut size = LOOKUP_BUFFER;
+    loop {
+        let mut buf = vec![0 as libc::c_char; size];
+        // SAFETY: passwd and group are plain old data; all-zero is valid.
+        let mut entry: T = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        match call(&mut entry, buf.as_mut_ptr(), size, &mut result) {
+            0 if result.is_null() => return Ok(None),
+            0 => return Ok(Some(entry)),
+            libc::ERANGE => size *= 2,
+            code => return Err(io::Error::from_raw_os_error(code)),
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use crate::orchestrator::cache::{CacheContext, lock_entry};
+use crate::orchestrator::config::{ConfigError, Step};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const COMPLETE_MARKER: &str = ".complete";
+
+/// Returns the interpreter for a python step: a cached virtualenv holding
+/// `requirements`, or the host `python3` when there are none.
+pub(crate) fn python_interpreter(
+    step: &Step,
+    envs: &[(String, String)],
+    cache: &CacheContext,
+) -> Result<String, Box<dyn Error>> {
+    let requirements = match &step.requirements {
+        Some(requirements) if !requirements.is_empty() => requirements,
+        _ => return Ok("python3".to_string()),
+    };
+
+    let dir = cache.deps_path("python", &dependency_keys(requirements, cache));
+    let venv = dir.join("venv");
+    let python = venv.join("bin").join("python3");
+    if is_complete(&dir) {
+        return Ok(python.to_string_lossy().to_string());
+    }
+    let _lock = lock_entry(&dir)?;
+    if is_complete(&dir) {
+        return Ok(python.to_string_lossy().to_string());
+    }
+
+    println!("deps: python requirements={}", requirements.join(" "));
+    reset_dir(&dir)?;
+    run_install(
+        Command::new("python3").arg("-m").arg("venv").arg(&venv),
+        envs,
+    )?;
+    run_install(
+        Command::new(&python)
+            .arg("-m")
+            .arg("pip")
+            .arg("install")
+            .arg("--quiet")
+            .args(requirements.iter().flat_map(|spec| spec_args(spec))),
+        envs,
+    )?;
+    mark_complete(&dir)?;
+    Ok(python.to_string_lossy().to_string())
+}
+
+/// Returns the cached `node_modules` directory holding `packages`, if any.
+pub(crate) fn node_modules(
+    step: &Step,
+    envs: &[(String, String)],
+    cache: &CacheContext,
+) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    let packages = match &step.packages {
+        Some(packages) if !packages.is_empty() => packages,
+        _ => return Ok(None),
+    };
+
+    let dir = cache.deps_path("node", &dependency_keys(packages, cache));
+    let modules = dir.join("node_modules");
+    if is_complete(&dir) {
+        return Ok(Some(modules));
+    }
+    let _lock = lock_entry(&dir)?;
+    if is_complete(&dir) {
+        return Ok(Some(modules));
+    }
+
+    println!("deps: node packages={}", packages.join(" "));
+    reset_dir(&dir)?;
+    // npm resolves file specs against --prefix, so vendored tarballs and
+    // directories are made absolute first.
+    let specs = packages
+        .iter()
+        .flat_map(|spec| spec_args(spec))
+        .map(absolute_if_local)
+        .collect::<Vec<_>>();
+    run_install(
+        Command::new("npm")
+            .arg("install")
+            .arg("--prefix")
+            .arg(&dir)
+            .arg("--no-audit")
+            .arg("--no-fund")
+            .arg("--silent")
+            .args(&specs),
+        envs,
+    )?;
+    mark_complete(&dir)?;
+    Ok(Some(modules))
+}
+
+/// Cache key parts for `specs`: each spec plus the content hash of any local
+/// path among its arguments (vendored wheels and tarballs, `-r` files,
+/// directories), so editing one reinstalls the set.
+fn dependency_keys(specs: &[String], cache: &CacheContext) -> Vec<String> {
+    specs
+        .iter()
+        .map(|spec| {
+            let mut key = spec.clone();
+            for arg in spec_args(spec) {
+                let path = Path::new(arg);
+                if path.exists()
+                    && let Some(hash) = cache.content_hash(path)
+                {
+                    key.push(' ');
+                    key.push_str(&hash);
+                }
+            }
+            key
+        })
+        .collect()
+}
+
+/// The installer arguments of one spec: an option such as `-r file` or
+/// `--index-url URL` is split on whitespace, anything else is one argument
+/// (`numpy >= 2` stays a single requirement).
+fn spec_args(spec: &str) -> Vec<&str> {
+    if spec.trim_start().starts_with('-') {
+        spec.split_whitespace().collect()
+    } else {
+        vec![spec]
+    }
+}
+
+fn absolute_if_local(spec: &str) -> String {
+    let path = Path::new(spec);
+    if (spec.starts_with('.') || spec.starts_with('/'))
+        && let Ok(absolute) = path.canonicalize()
+    {
+        return absolute.to_string_lossy().to_string();
+    }
+    spec.to_string()
+}
+
+fn is_complete(dir: &Path) -> bool {
+    dir.join(COMPLETE_MARKER).exists()
+}
+
+fn mark_complete(dir: &Path) -> Result<(), Box<dyn Error>> {
+    std::fs::write(dir.join(COMPLETE_MARKER), b"")?;
+    Ok(())
+}
+
+/// Clears a partially installed environment left by an interrupted run; the
+/// caller holds the entry's lock.
+fn reset_dir(dir: &Path) -> Result<(), Box<dyn Error>> {
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+    }
+    std::fs::create_dir_all(dir)?;
+    Ok(())
+}
+
+fn run_install(command: &mut Command, envs: &[(String, String)]) -> Result<(), Box<dyn Error>> {
+    for (key, value) in envs {
+        command.env(key, value);
+    }
+    let status = command.status()?;
+    if !status.success() {
+        return Err(Box::new(ConfigError(format!(
+            "dependency install failed: {:?}",
+            command
+        ))));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(name: &str) -> CacheContext {
+        let base_dir =
+            std::env::temp_dir().join(format!("wl-deps-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&base_dir);
+        std::fs::create_dir_all(&base_dir).unwrap();
+        CacheContext {
+            config_hash: String::new(),
+            run_id: "test".to_string(),
+            run_dir: base_dir.join("runs").join("test"),
+            source_dir: base_dir.join("source"),
+            url_dir: base_dir.join("url"),
+            git_dir: base_dir.join("git"),
+            base_dir,
+            refresh: None,
+            offline: false,
+        }
+    }
+
+    #[test]
+    fn options_split_into_arguments() {
+        assert_eq!(spec_args("-r requirements.txt"), ["-r", "requirements.txt"]);
+        assert_eq!(
+            spec_args("--index-url  https://example.test/simple"),
+            ["--index-url", "https://example.test/simple"]
+        );
+        assert_eq!(spec_args("numpy >= 2"), ["numpy >= 2"]);
+    }
+
+    #[test]
+    fn requirements_file_contents_key_the_install() {
+        let cache = cache("requirements");
+        let file = cache.base_dir.join("requirements.txt");
+        std::fs::write(&file, "numpy==2.1.0\n").unwrap();
+        let specs = [format!("-r {}", file.display())];
+        let before = dependency_keys(&specs, &cache);
+        assert_ne!(before[0], specs[0]);
+        assert_eq!(dependency_keys(&specs, &cache), before);
+
+        std::fs::write(&file, "numpy==2.2.0\n").unwrap();
+        assert_ne!(dependency_keys(&specs, &cache), before);
+    }
+
+    #[test]
+    fn vendored_package_contents_key_the_install() {
+        let cache = cache("vendored");
+        let wheel = cache.base_dir.join("pkg-1.0-py3-none-any.whl");
+        std::fs::write(&wheel, b"one").unwrap();
+        let specs = [wheel.display().to_string(), "requests==2.32.3".to_string()];
+        let before = dependency_keys(&specs, &cache);
+        assert_eq!(before[1], specs[1]);
+
+        std::fs::write(&wheel, b"two").unwrap();
+        let after = dependency_keys(&specs, &cache);
+        assert_ne!(after[0], before[0]);
+        assert_eq!(after[1], before[1]);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::orchestrator::cache::{CacheContext, lock_entry};
+use crate::orchestrator::config::{ConfigError, GitSource};
+use crate::orchestrator::source::RefreshPolicy;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Resolves a git source to a checkout pinned at the commit `ref` points to.
+/// Returns the directory to build or run from (the checkout or `git.dir`).
+pub(crate) fn resolve_git_source(
+    git: &GitSource,
+    policy: RefreshPolicy,
+    cache: &CacheContext,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let git_ref = git.git_ref.as_deref().unwrap_or("HEAD");
+    let mirror = cache.git_mirror_path(&git.repo);
+    // Steps sharing a repo clone, fetch and add worktrees one at a time.
+    let _lock = lock_entry(&mirror)?;
+
+    if !mirror.exists() {
+        if cache.offline {
+            return Err(Box::new(ConfigError(format!(
+                "git repo '{}' is not cached (offline mode)",
+                git.repo
+            ))));
+        }
+        run_git(
+            Command::new("git")
+                .arg("clone")
+                .arg("--mirror")
+                .arg("--quiet")
+                .arg(&git.repo)
+                .arg(&mirror),
+        )?;
+    } else if !cache.offline
+        && (policy != RefreshPolicy::Never || resolve_commit(&mirror, git_ref).is_err())
+    {
+        run_git(
+            Command::new("git")
+                .arg("-C")
+                .arg(&mirror)
+                .arg("fetch")
+                .arg("--prune")
+                .arg("--quiet"),
+        )?;
+    }
+
+    let commit = resolve_commit(&mirror, git_ref)?;
+    let checkout = cache.git_checkout_path(&git.repo, &commit);
+    if !checkout.exists() {
+        run_git(
+            Command::new("git")
+                .arg("-C")
+                .arg(&mirror)
+                .arg("worktree")
+                .arg("add")
+                .arg("--detach")
+                .arg("--quiet")
+                .arg(&checkout)
+                .arg(&commit),
+        )?;
+    }
+    println!(
+        "source: git repo={} ref={} commit={}",
+        git.repo, git_ref, commit
+    );
+
+    let root = match &git.dir {
+        Some(dir) => checkout.join(dir),
+        None => checkout,
+    };
+    if !root.is_dir() {
+        return Err(Box::new(ConfigError(format!(
+            "git.dir '{}' does not exist in repo '{}'",
+            git.dir.as_deref().unwrap_or(""),
+            git.repo
+        ))));
+    }
+    Ok(root)
+}
+
+pub(crate) fn ensure_git_cached(
+    git: &GitSource,
+    cache: &CacheContext,
+) -> Result<(), Box<dyn Error>> {
+    if cache.git_mirror_path(&git.repo).exists() {
+        return Ok(());
+    }
+    Err(Box::new(ConfigError(format!(
+        "git repo '{}' is not cached (offline mode)",
+        git.repo
+    ))))
+}
+
+fn resolve_commit(mirror: &Path, git_ref: &str) -> Result<String, Box<dyn Error>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(mirror)
+        .arg("rev-parse")
+        .arg("--verify")
+        .arg("--quiet")
+        .arg(format!("{}^{{commit}}", git_ref))
+        .output()?;
+    if !output.status.success() {
+        return Err(Box::new(ConfigError(format!(
+            "git ref '{}' not found",
+            git_ref
+        ))));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn run_git(command: &mut Command) -> Result<(), Box<dyn Error>> {
+    let status = command.status()?;
+    if !status.success() {
+        return Err(Box::new(ConfigError(format!(
+            "git command failed: {:?}",
+            command
+        ))));
+    }
+    Ok(())
+}
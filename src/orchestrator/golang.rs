@@ -1,22 +1,20 @@
 use crate::orchestrator::cache::CacheContext;
-use crate::orchestrator::config::{Step, step_duration_ms, step_env, step_processes, step_stdout};
-use crate::orchestrator::process::{kill_process, spawn_process, wait_process};
-use crate::orchestrator::StepOutcome;
-use crate::orchestrator::source::resolve_source;
+use crate::orchestrator::config::{Step, step_env, step_processes, step_stdout};
+use crate::orchestrator::controls::StepControls;
+use crate::orchestrator::process::PendingProcess;
+use crate::orchestrator::source::{ResolvedSource, resolve_source};
+use crate::orchestrator::templating::{ProcessVars, expand_process_tokens};
 use crate::orchestrator::wrapper::wrap_command;
 use std::error::Error;
 use std::path::Path;
-use std::thread;
-use std::time::Duration;
 
-pub(crate) fn run(
+pub(crate) fn launch(
     step: &Step,
     cache: &CacheContext,
-    on_start: &dyn Fn(&[u32]),
-) -> Result<StepOutcome, Box<dyn Error>> {
+    controls: &StepControls,
+) -> Result<Vec<PendingProcess>, Box<dyn Error>> {
     let processes = step_processes(step);
     let stdout_enabled = step_stdout(step);
-    let duration_ms = step_duration_ms(step);
     let source = resolve_source(step, Path::new("runtimes/golang/main.go"), "go", cache)?;
     let args = step.args.as_deref().unwrap_or(&[]);
     let envs = step_env(step);
@@ -26,49 +24,54 @@ pub(crate) fn run(
     let step_id = step.id.as_deref().unwrap_or("unknown");
     let log_label = format!("step={} runtime=golang", step_id);
 
-    let exec_path = build_go_binary(&source.path, &envs, cache)?;
-    let mut children = Vec::new();
-    let mut pids = Vec::new();
-    for _ in 0..processes {
+    let exec_path = build_go_binary(&source, &envs, cache)?;
+    let mut pending = Vec::new();
+    for proc_index in 0..processes {
         let mut base = vec![exec_path.to_string_lossy().to_string()];
         base.extend(args.iter().cloned());
-        let wrapped = wrap_command(step, &base);
+        let vars = ProcessVars {
+            step_id,
+            run_dir: &cache.run_dir,
+            proc_index,
+        };
+        let wrapped = wrap_command(step, &base, &vars)?;
         let mut command = wrapped.command;
+        let settings = controls.prepare(&mut command, &vars)?;
         for (key, value) in &envs {
-            command.env(key, value);
+            command.env(key, expand_process_tokens(value, &vars));
         }
-        let child = spawn_process(command, &log_label, &wrapped.display, stdout_enabled)?;
-        pids.push(child.pid());
-        children.push(child);
+        pending.push(PendingProcess {
+            command,
+            log_label: log_label.clone(),
+            settings,
+            display: wrapped.display,
+            stdout_enabled,
+        });
     }
 
-    on_start(&pids);
-
-    if let Some(duration) = duration_ms {
-        thread::sleep(Duration::from_millis(duration));
-        for child in &mut children {
-            let _ = kill_process(child);
-        }
-    }
-
-    let mut exit_codes = Vec::new();
-    for child in children {
-        let code = wait_process(child, &log_label, duration_ms.is_some())?;
-        exit_codes.push(code);
-    }
-
-    println!("pids={}", join_pids(&pids));
-    println!("golang: done");
-
-    Ok(StepOutcome { pids, exit_codes })
+    Ok(pending)
 }
 
 fn build_go_binary(
-    source_path: &Path,
+    source: &ResolvedSource,
     envs: &[(String, String)],
     cache: &CacheContext,
 ) -> Result<std::path::PathBuf, Box<dyn Error>> {
-    let output_path = cache.build_path_for_source(source_path);
+    // Multi-file sources build the package directory so go.mod and sibling
+    // files are picked up; single files are built on their own.
+    let package_dir = source.dir.as_ref().map(|_| {
+        if source.path.is_dir() {
+            source.path.clone()
+        } else {
+            source
+                .path
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| source.path.clone())
+        }
+    });
+    let build_key = package_dir.as_deref().unwrap_or(&source.path);
+    let output_path = cache.build_path_for_source(build_key);
     if output_path.exists() {
         return Ok(output_path);
     }
@@ -78,11 +81,15 @@ fn build_go_binary(
     }
 
     let mut command = std::process::Command::new("go");
-    command
-        .arg("build")
-        .arg("-o")
-        .arg(&output_path)
-        .arg(source_path);
+    command.arg("build").arg("-o").arg(&output_path);
+    match &package_dir {
+        Some(dir) => {
+            command.current_dir(dir).arg(".");
+        }
+        None => {
+            command.arg(&source.path);
+        }
+    }
     for (key, value) in envs {
         command.env(key, value);
     }
@@ -93,9 +100,3 @@ fn build_go_binary(
 
     Ok(output_path)
 }
-fn join_pids(pids: &[u32]) -> String {
-    pids.iter()
-        .map(|pid| pid.to_string())
-        .collect::<Vec<_>>()
-        .join(",")
-}
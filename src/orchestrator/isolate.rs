@@ -0,0 +1,336 @@
+use crate::orchestrator::config::{ConfigError, IsolateSpec, Step};
+use crate::orchestrator::process::SpawnSettings;
+use std::error::Error;
+use std::ffi::CStr;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::ptr;
+
+/// Names accepted in `isolate` and the namespace each one unshares.
+pub(crate) const NAMESPACES: &[(&str, libc::c_int)] = &[
+    ("pid", libc::CLONE_NEWPID),
+    ("mount", libc::CLONE_NEWNS),
+    ("net", libc::CLONE_NEWNET),
+    ("uts", libc::CLONE_NEWUTS),
+];
+
+/// Highest descriptor the shim checks when it closes what exec would have.
+const MAX_FDS: u64 = 1 << 20;
+
+/// Namespaces a step runs in, in table order; `None` when a name is unknown.
+pub(crate) fn namespaces(spec: &IsolateSpec) -> Option<Vec<&'static str>> {
+    match spec {
+        IsolateSpec::All(true) => Some(NAMESPACES.iter().map(|(name, _)| *name).collect()),
+        IsolateSpec::All(false) => Some(Vec::new()),
+        IsolateSpec::List(names) => {
+            if names.iter().any(|name| namespace_flag(name).is_none()) {
+                return None;
+            }
+            Some(
+                NAMESPACES
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .filter(|name| names.iter().any(|wanted| wanted == name))
+                    .collect(),
+            )
+        }
+    }
+}
+
+fn namespace_flag(name: &str) -> Option<libc::c_int> {
+    NAMESPACES
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, flag)| *flag)
+}
+
+/// The namespaces every process of a step is started in.
+pub(crate) struct Isolation {
+    flags: libc::c_int,
+    /// `uid_map` and `gid_map` lines mapping `wl`'s user to root inside a new
+    /// user namespace, used when `wl` is not root.
+    id_maps: Option<(Vec<u8>, Vec<u8>)>,
+    hostname: Vec<u8>,
+    fields: String,
+}
+
+/// Collects the step's namespaces; `None` when it is not isolated. `cwd` and
+/// `workspace` must stay visible under the private /tmp of a mount namespace.
+pub(crate) fn isolation(
+    step: &Step,
+    cwd: &Path,
+    workspace: &Path,
+) -> Result<Option<Isolation>, Box<dyn Error>> {
+    let names = step
+        .isolate
+        .as_ref()
+        .and_then(namespaces)
+        .unwrap_or_default();
+    if names.is_empty() {
+        return Ok(None);
+    }
+    let mut flags = names
+        .iter()
+        .filter_map(|name| namespace_flag(name))
+        .fold(0, |flags, flag| flags | flag);
+    if flags & libc::CLONE_NEWNS != 0 {
+        for (what, path) in [
+            ("cwd", cwd),
+            ("workspace", &std::path::absolute(workspace)?),
+        ] {
+            if path.starts_with("/tmp") {
+                return Err(Box::new(ConfigError(format!(
+                    "isolate 'mount' gives the step a private /tmp, which would hide its {} '{}'",
+                    what,
+                    path.display()
+                ))));
+            }
+        }
+    }
+    let mut fields = format!(" isolate={}", names.join(","));
+
+    // SAFETY: geteuid and getegid cannot fail.
+    let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let id_maps = if uid == 0 {
+        None
+    } else {
+        user_namespaces_available()?;
+        flags |= libc::CLONE_NEWUSER;
+        fields.push_str(",user");
+        Some((
+            format!("0 {} 1", uid).into_bytes(),
+            format!("0 {} 1", gid).into_bytes(),
+        ))
+    };
+
+    let mut hostname = step.id.as_deref().unwrap_or("wl").as_bytes().to_vec();
+    hostname.truncate(64);
+    Ok(Some(Isolation {
+        flags,
+        id_maps,
+        hostname,
+        fields,
+    }))
+}
+
+/// Fails clearly when the kernel does not let unprivileged users create user
+/// namespaces.
+fn user_namespaces_available() -> Result<(), Box<dyn Error>> {
+    let switches = [
+        (
+            "/proc/sys/user/max_user_namespaces",
+            "user.max_user_namespaces",
+        ),
+        (
+            "/proc/sys/kernel/unprivileged_userns_clone",
+            "kernel.unprivileged_userns_clone",
+        ),
+    ];
+    for (path, name) in switches {
+        if std::fs::read_to_string(path).is_ok_and(|value| value.trim() == "0") {
+            return Err(Box::new(ConfigError(format!(
+                "isolate needs root or unprivileged user namespaces ({} is 0)",
+                name
+            ))));
+        }
+    }
+    Ok(())
+}
+
+impl Isolation {
+    /// Starts the process in new namespaces. The spawned process unshares
+    /// them and forks the workload, which becomes PID 1 of the new PID
+    /// namespace; it then stays behind as a shim that reports the workload's
+    /// host PID through `settings.host_pid` and exits the way it does.
+    /// Register it after every other `pre_exec` hook so those apply to both.
+    pub(crate) fn prepare(
+        &self,
+        command: &mut Command,
+        settings: &mut SpawnSettings,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut fds = [0; 2];
+        // SAFETY: `fds` is a live array with room for both descriptors.
+        check(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) })?;
+        // SAFETY: pipe2 just opened both descriptors and nothing else owns them.
+        let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        settings.host_pid = Some(read);
+        settings.fields.push_str(&self.fields);
+
+        let flags = self.flags;
+        let id_maps = self.id_maps.clone();
+        let hostname = self.hostname.clone();
+        // SAFETY: the hook only makes raw syscalls on values it owns, which
+        // is async-signal-safe; it forks with a raw clone so no atfork
+        // handlers run.
+        unsafe {
+            command.pre_exec(move || {
+                // The shim must not outlive the `wl` thread waiting for it.
+                libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+                check(libc::unshare(flags))?;
+                if let Some((uid_map, gid_map)) = &id_maps {
+                    write_file(c"/proc/self/setgroups", b"deny")?;
+                    write_file(c"/proc/self/uid_map", uid_map)?;
+                    write_file(c"/proc/self/gid_map", gid_map)?;
+                }
+                if flags & libc::CLONE_NEWNS != 0 {
+                    check(libc::mount(
+                        ptr::null(),
+                        c"/".as_ptr(),
+                        ptr::null(),
+                        libc::MS_REC | libc::MS_PRIVATE,
+                        ptr::null(),
+                    ))?;
+                    check(libc::mount(
+                        c"tmpfs".as_ptr(),
+                        c"/tmp".as_ptr(),
+                        c"tmpfs".as_ptr(),
+                        libc::MS_NOSUID | libc::MS_NODEV,
+                        ptr::null(),
+                    ))?;
+                }
+                if flags & libc::CLONE_NEWUTS != 0 {
+                    check(libc::sethostname(hostname.as_ptr().cast(), hostname.len()))?;
+                }
+                if flags & libc::CLONE_NEWNET != 0 {
+                    loopback_up()?;
+                }
+
+                let none: libc::c_long = 0;
+                let pid = libc::syscall(
+                    libc::SYS_clone,
+                    libc::SIGCHLD as libc::c_long,
+                    none,
+                    none,
+                    none,
+                    none,
+                ) as libc::pid_t;
+                match pid {
+                    -1 => Err(io::Error::last_os_error()),
+                    0 => {
+                        // Killing the shim takes the whole namespace down.
+                        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+                        if flags & libc::CLONE_NEWPID != 0 && flags & libc::CLONE_NEWNS != 0 {
+                            check(libc::mount(
+                                c"proc".as_ptr(),
+                                c"/proc".as_ptr(),
+                                c"proc".as_ptr(),
+                                libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                                ptr::null(),
+                            ))?;
+                        }
+                        Ok(())
+                    }
+                    pid => run_shim(pid, write.as_raw_fd()),
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Reports the workload's host PID, waits for it and exits with its status,
+/// so `wl` sees the workload's exit code or signal and, through wait4, its
+/// resource usage.
+fn run_shim(pid: libc::pid_t, host_pid: RawFd) -> ! {
+    // SAFETY: only raw syscalls on locals; the shim never returns to the
+    // code that forked it.
+    unsafe {
+        let bytes = (pid as u32).to_ne_bytes();
+        libc::write(host_pid, bytes.as_ptr().cast(), bytes.len());
+        close_cloexec_fds();
+        for signal in [libc::SIGHUP, libc::SIGINT, libc::SIGQUIT, libc::SIGTERM] {
+            libc::signal(signal, libc::SIG_IGN);
+        }
+
+        let mut status = 0;
+        while libc::waitpid(pid, &mut status, 0) < 0 {
+            if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                libc::_exit(127);
+            }
+        }
+        if libc::WIFEXITED(status) {
+            libc::_exit(libc::WEXITSTATUS(status));
+        }
+
+        let signal = libc::WTERMSIG(status);
+        let no_core = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+        libc::signal(signal, libc::SIG_DFL);
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, signal);
+        libc::sigprocmask(libc::SIG_UNBLOCK, &set, ptr::null_mut());
+        libc::kill(libc::getpid(), signal);
+        libc::_exit(128 + signal)
+    }
+}
+
+/// Closes what exec would have, since the shim never execs: `Command::spawn`
+/// waits until its close-on-exec status pipe is closed in every process.
+fn close_cloexec_fds() {
+    // SAFETY: rlimit is plain old data; fcntl and close only take integers.
+    unsafe {
+        let mut limit: libc::rlimit = std::mem::zeroed();
+        libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit);
+        let max = limit.rlim_cur.min(MAX_FDS) as RawFd;
+        for fd in 3..max {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags >= 0 && flags & libc::FD_CLOEXEC != 0 {
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+/// Brings up `lo`, the only interface in a new network namespace.
+fn loopback_up() -> io::Result<()> {
+    // SAFETY: ifreq is plain old data; the ioctls only access the live local.
+    unsafe {
+        let sock = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        check(sock)?;
+        let mut request: libc::ifreq = std::mem::zeroed();
+        for (slot, byte) in request.ifr_name.iter_mut().zip(b"lo") {
+            *slot = *byte as libc::c_char;
+        }
+        let mut result = libc::ioctl(sock, libc::SIOCGIFFLAGS, &mut request);
+        if result == 0 {
+            request.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+            result = libc::ioctl(sock, libc::SIOCSIFFLAGS, &request);
+        }
+        let error = io::Error::last_os_error();
+        libc::close(sock);
+        if result < 0 {
+            return Err(error);
+        }
+        Ok(())
+    }
+}
+
+fn write_file(path: &CStr, data: &[u8]) -> io::Result<()> {
+    // SAFETY: open/write/close are async-signal-safe and only read `path`
+    // and `data`.
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        check(fd)?;
+        let written = libc::write(fd, data.as_ptr().cast(), data.len());
+        let error = io::Error::last_os_error();
+        libc::close(fd);
+        if written < 0 {
+            return Err(error);
+        }
+        Ok(())
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,482 @@
+use crate::orchestrator::config::{ConfigError, LimitValue, Limits, Step};
+use crate::orchestrator::process::SpawnSettings;
+use crate::orchestrator::rlimits::{Rlimit, install_rlimits};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Mutex, OnceLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// `cpu.max` period used when the quota is given as a CPU count.
+const CPU_PERIOD_USEC: u64 = 100_000;
+
+static DEFAULT_PARENT: OnceLock<Result<PathBuf, String>> = OnceLock::new();
+
+/// Threads removing cgroups whose leftover processes are being killed.
+static REMOVALS: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
+/// cgroup counters recorded in the step summary.
+#[derive(Debug, Serialize)]
+pub(crate) struct CgroupReport {
+    pub(crate) path: String,
+    pub(crate) cpu_stat: BTreeMap<String, u64>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) memory_events: BTreeMap<String, u64>,
+    /// `io.stat` counters keyed by device (`major:minor`).
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) io_stat: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+/// Limits in effect for a step: a transient cgroup, or the `setrlimit`
+/// fallback when no cgroup could be set up.
+pub(crate) enum AppliedLimits {
+    Cgroup(Cgroup),
+    Rlimits(Vec<Rlimit>),
+}
+
+/// A cgroup created for one step and removed when dropped.
+pub(crate) struct Cgroup {
+    path: PathBuf,
+    procs: CString,
+    memory_limited: bool,
+}
+
+/// Creates `<parent>/wl-<run>-<step>` with the step's limits. When that fails
+/// the step errors, unless `fallback: rlimit` asks for `setrlimit` instead.
+pub(crate) fn apply_limits(
+    limits: &Limits,
+    run_id: &str,
+    step_id: &str,
+) -> Result<AppliedLimits, Box<dyn Error>> {
+    let name = format!("wl-{}-{}", run_id, step_id.replace('/', "_"));
+    let reason = match create_cgroup(limits, &name) {
+        Ok(cgroup) => return Ok(AppliedLimits::Cgroup(cgroup)),
+        Err(reason) => reason,
+    };
+    if limits.fallback.as_deref() != Some("rlimit") {
+        return Err(Box::new(ConfigError(format!(
+            "step '{}': cannot apply cgroup limits: {} (point limits.parent at a writable cgroup v2 \
+             with the controllers delegated, or set limits.fallback: rlimit)",
+            step_id, reason
+        ))));
+    }
+
+    println!(
+        "limits: step={} cgroup unavailable ({}); falling back to setrlimit",
+        step_id, reason
+    );
+    let unenforced = [
+        ("cpu_max", limits.cpu_max.is_some()),
+        ("memory_high", limits.memory_high.is_some()),
+        ("io_max", limits.io_max.is_some()),
+    ]
+    .into_iter()
+    .filter_map(|(name, set)| set.then_some(name))
+    .collect::<Vec<_>>();
+    if !unenforced.is_empty() {
+        println!(
+            "limits: step={} not enforced without cgroups: {}",
+            step_id,
+            unenforced.join(", ")
+        );
+    }
+    let mut rlimits = Vec::new();
+    if let Some(bytes) = limits.memory_max.as_ref().and_then(memory_bytes) {
+        rlimits.push(Rlimit {
+            resource: libc::RLIMIT_AS,
+            soft: bytes,
+            hard: bytes,
+        });
+    }
+    if let Some(count) = limits.pids_max.as_ref().and_then(pids_count) {
+        println!(
+            "limits: step={} pids_max applied as RLIMIT_NPROC, which counts every process of the \
+             user, not just the step's",
+            step_id
+        );
+        rlimits.push(Rlimit {
+            resource: libc::RLIMIT_NPROC,
+            soft: count,
+            hard: count,
+        });
+    }
+    Ok(AppliedLimits::Rlimits(rlimits))
+}
+
+impl AppliedLimits {
+    /// Makes the child join the cgroup, or set its rlimits, before exec.
+    pub(crate) fn prepare(&self, command: &mut Command, settings: &mut SpawnSettings) {
+        match self {
+            AppliedLimits::Cgroup(cgroup) => {
+                let procs = cgroup.procs.clone();
+                // SAFETY: the hook only calls open/write/close, which are
+                // async-signal-safe, on memory owned by the closure.
+                unsafe {
+                    command.pre_exec(move || join_cgroup(&procs));
+                }
+                settings.memory_limited |= cgroup.memory_limited;
+                settings.memory_events = Some(cgroup.path.join("memory.events"));
+            }
+            AppliedLimits::Rlimits(rlimits) => {
+                install_rlimits(command, rlimits.clone());
+                settings.memory_limited |= rlimits
+                    .iter()
+                    .any(|limit| limit.resource == libc::RLIMIT_AS);
+            }
+        }
+    }
+
+    /// Reads the cgroup counters and removes the cgroup; call once every
+    /// process of the step has been reaped.
+    pub(crate) fn finish(self, step_id: &str) -> Option<CgroupReport> {
+        let AppliedLimits::Cgroup(cgroup) = self else {
+            return None;
+        };
+        let report = CgroupReport {
+            path: cgroup.path.to_string_lossy().to_string(),
+            cpu_stat: read_flat(&cgroup.path.join("cpu.stat")),
+            memory_events: read_flat(&cgroup.path.join("memory.events")),
+            io_stat: read_nested(&cgroup.path.join("io.stat")),
+        };
+        let mut counters = Vec::new();
+        for key in ["usage_usec", "nr_throttled", "throttled_usec"] {
+            if let Some(value) = report.cpu_stat.get(key) {
+                counters.push(format!("{}={}", key, value));
+            }
+        }
+        for key in ["high", "max", "oom_kill"] {
+            if let Some(value) = report.memory_events.get(key) {
+                counters.push(format!("memory.{}={}", key, value));
+            }
+        }
+        println!("limits: step={} {}", step_id, counters.join(" "));
+        Some(report)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if fs::remove_dir(&self.path).is_ok() {
+            return;
+        }
+        // Descendants left behind (e.g. forked by a shell) go with the group.
+        // Waiting for them happens off the caller's thread, which is the
+        // supervisor's event loop.
+        let _ = fs::write(self.path.join("cgroup.kill"), "1");
+        let path = self.path.clone();
+        let handle = std::thread::spawn(move || {
+            for _ in 0..50 {
+                std::thread::sleep(Duration::from_millis(20));
+                if fs::remove_dir(&path).is_ok() {
+                    return;
+                }
+            }
+            println!("limits: could not remove cgroup {}", path.display());
+        });
+        REMOVALS.lock().unwrap().push(handle);
+    }
+}
+
+/// Waits for cgroups still being emptied after their step ended.
+pub(crate) fn finish_removals() {
+    let handles = std::mem::take(&mut *REMOVALS.lock().unwrap());
+    for handle in handles {
+        let _ = handle.join();
+    }
+}
+
+/// Moves `wl` out of its own cgroup into a `wl` leaf below it when a step
+/// uses that cgroup as its default parent: controllers can only be enabled
+/// for children of a cgroup without member processes. Call before any step
+/// starts, so no child is left behind in the parent.
+pub(crate) fn prepare_default_parent(steps: &[Step]) {
+    let uses_default = steps.iter().any(|step| {
+        step.limits
+            .as_ref()
+            .is_some_and(|limits| limits.parent.is_none())
+    });
+    if uses_default {
+        let _ = default_parent();
+    }
+}
+
+/// `wl`'s cgroup as found at startup, once `wl` has moved into its leaf.
+fn default_parent() -> Result<PathBuf, String> {
+    DEFAULT_PARENT
+        .get_or_init(|| {
+            let mount = cgroup2_mount().ok_or("no cgroup2 filesystem is mounted")?;
+            let own = fs::read_to_string("/proc/self/cgroup")
+                .ok()
+                .and_then(|text| {
+                    text.lines()
+                        .find_map(|line| line.strip_prefix("0::").map(str::to_string))
+                })
+                .ok_or("cannot find the current cgroup v2 path")?;
+            let own = match own.trim().trim_matches('/') {
+                // The root cgroup may have both members and children.
+                "" => return Ok(mount),
+                own => mount.join(own),
+            };
+            let leaf = own.join("wl");
+            match fs::create_dir(&leaf) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(format!("cannot create {}: {}", leaf.display(), err)),
+            }
+            fs::write(leaf.join("cgroup.procs"), std::process::id().to_string())
+                .map_err(|err| format!("cannot move wl into {}: {}", leaf.display(), err))?;
+            println!("limits: moved wl into {}", leaf.display());
+            Ok(own)
+        })
+        .clone()
+}
+
+fn create_cgroup(limits: &Limits, name: &str) -> Result<Cgroup, String> {
+    let parent = cgroup_parent(limits.parent.as_deref())?;
+    let files = limit_files(limits);
+    let mut controllers = Vec::new();
+    for (file, _) in &files {
+        let controller = file.split('.').next().unwrap_or(file);
+        if !controllers.contains(&controller) {
+            controllers.push(controller);
+        }
+    }
+    enable_controllers(&parent, &controllers)?;
+
+    let path = parent.join(name);
+    let procs = CString::new(path.join("cgroup.procs").as_os_str().as_bytes())
+        .map_err(|_| format!("invalid cgroup path {}", path.display()))?;
+    fs::create_dir(&path).map_err(|err| format!("cannot create {}: {}", path.display(), err))?;
+    let cgroup = Cgroup {
+        path,
+        procs,
+        memory_limited: limits.memory_max.is_some() || limits.memory_high.is_some(),
+    };
+    for (file, value) in &files {
+        fs::write(cgroup.path.join(file), value)
+            .map_err(|err| format!("cannot write {} '{}': {}", file, value, err))?;
+    }
+
+    let mut line = format!("limits: cgroup={}", cgroup.path.display());
+    for (file, value) in &files {
+        line.push_str(&format!(" {}={}", file, value.replace(' ', ",")));
+    }
+    println!("{}", line);
+    Ok(cgroup)
+}
+
+/// Interface files to write, in order. `io.max` takes one device per write.
+fn limit_files(limits: &Limits) -> Vec<(&'static str, String)> {
+    let mut files = Vec::new();
+    if let Some(line) = limits.cpu_max.as_ref().and_then(cpu_max_line) {
+        files.push(("cpu.max", line));
+    }
+    if let Some(line) = limits.memory_high.as_ref().and_then(memory_line) {
+        files.push(("memory.high", line));
+    }
+    if let Some(line) = limits.memory_max.as_ref().and_then(memory_line) {
+        files.push(("memory.max", line));
+    }
+    for line in limits.io_max.iter().flatten() {
+        files.push(("io.max", line.trim().to_string()));
+    }
+    if let Some(line) = limits.pids_max.as_ref().and_then(pids_line) {
+        files.push(("pids.max", line));
+    }
+    files
+}
+
+/// An absolute `parent` inside the cgroup2 mount is used as is; any other
+/// path is relative to the mount. Without one, the cgroup `wl` started in is
+/// used (see `prepare_default_parent`).
+fn cgroup_parent(parent: Option<&str>) -> Result<PathBuf, String> {
+    let Some(parent) = parent else {
+        return default_parent();
+    };
+    let mount = cgroup2_mount().ok_or("no cgroup2 filesystem is mounted")?;
+    if Path::new(parent).starts_with(&mount) {
+        return Ok(parent.into());
+    }
+    match parent.trim_matches('/') {
+        "" => Ok(mount),
+        relative => Ok(mount.join(relative)),
+    }
+}
+
+fn cgroup2_mount() -> Option<PathBuf> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo.lines().find_map(|line| {
+        let (mount, filesystem) = line.split_once(" - ")?;
+        if filesystem.split_whitespace().next() != Some("cgroup2") {
+            return None;
+        }
+        mount.split_whitespace().nth(4).map(PathBuf::from)
+    })
+}
+
+fn enable_controllers(parent: &Path, controllers: &[&str]) -> Result<(), String> {
+    let read = |file: &str| fs::read_to_string(parent.join(file)).unwrap_or_default();
+    let available = read("cgroup.controllers");
+    let enabled = read("cgroup.subtree_control");
+    for controller in controllers {
+        if !available.split_whitespace().any(|name| name == *controller) {
+            return Err(format!(
+                "controller '{}' is not available in {}",
+                controller,
+                parent.display()
+            ));
+        }
+        if enabled.split_whitespace().any(|name| name == *controller) {
+            continue;
+        }
+        fs::write(
+            parent.join("cgroup.subtree_control"),
+            format!("+{}", controller),
+        )
+        .map_err(|err| {
+            format!(
+                "cannot enable controller '{}' in {}: {}",
+                controller,
+                parent.display(),
+                err
+            )
+        })?;
+    }
+    Ok(())
+}
+
+fn join_cgroup(procs: &CStr) -> io::Result<()> {
+    // SAFETY: `procs` is a valid C string; the fd is closed before returning.
+    unsafe {
+        let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+        let error = io::Error::last_os_error();
+        libc::close(fd);
+        if written < 0 {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/// Reads `key value` lines such as `cpu.stat` and `memory.events`.
+fn read_flat(path: &Path) -> BTreeMap<String, u64> {
+    let text = fs::read_to_string(path).unwrap_or_default();
+    text.lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(' ')?;
+            Some((key.to_string(), value.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Reads `device key=value ...` lines such as `io.stat`.
+fn read_nested(path: &Path) -> BTreeMap<String, BTreeMap<String, u64>> {
+    let text = fs::read_to_string(path).unwrap_or_default();
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let device = fields.next()?.to_string();
+            let counters = fields
+                .filter_map(|field| {
+                    let (key, value) = field.split_once('=')?;
+                    Some((key.to_string(), value.parse().ok()?))
+                })
+                .collect();
+            Some((device, counters))
+        })
+        .collect()
+}
+
+/// `cpu.max` content for a CPU count, a percentage of one CPU, or a raw
+/// `<quota|max> [<period>]` line.
+pub(crate) fn cpu_max_line(value: &LimitValue) -> Option<String> {
+    let cpus = match value {
+        LimitValue::Number(cpus) => *cpus,
+        LimitValue::Text(text) => {
+            let text = text.trim();
+            if let Some(percent) = text.strip_suffix('%') {
+                percent.trim().parse::<f64>().ok()? / 100.0
+            } else if let Ok(cpus) = text.parse::<f64>() {
+                cpus
+            } else {
+                let fields = text.split_whitespace().collect::<Vec<_>>();
+                let quota =
+                    |field: &str| field == "max" || field.parse::<u64>().is_ok_and(|q| q > 0);
+                let valid = match fields.as_slice() {
+                    [first] => quota(first),
+                    [first, period] => {
+                        quota(first) && period.parse::<u64>().is_ok_and(|period| period > 0)
+                    }
+                    _ => false,
+                };
+                return valid.then(|| fields.join(" "));
+            }
+        }
+    };
+    if !cpus.is_finite() || cpus <= 0.0 {
+        return None;
+    }
+    let quota = ((cpus * CPU_PERIOD_USEC as f64).round() as u64).max(1000);
+    Some(format!("{} {}", quota, CPU_PERIOD_USEC))
+}
+
+/// `memory.max`/`memory.high` content: bytes or `max`.
+pub(crate) fn memory_line(value: &LimitValue) -> Option<String> {
+    match value {
+        LimitValue::Text(text) if text.trim() == "max" => Some("max".to_string()),
+        _ => memory_bytes(value).map(|bytes| bytes.to_string()),
+    }
+}
+
+/// `pids.max` content: a count or `max`.
+pub(crate) fn pids_line(value: &LimitValue) -> Option<String> {
+    match value {
+        LimitValue::Text(text) if text.trim() == "max" => Some("max".to_string()),
+        _ => pids_count(value).map(|count| count.to_string()),
+    }
+}
+
+pub(crate) fn memory_bytes(value: &LimitValue) -> Option<u64> {
+    match value {
+        LimitValue::Number(bytes) if *bytes >= 0.0 && bytes.fract() == 0.0 => Some(*bytes as u64),
+        LimitValue::Number(_) => None,
+        LimitValue::Text(text) => parse_size(text.trim()),
+    }
+}
+
+fn pids_count(value: &LimitValue) -> Option<u64> {
+    let count = match value {
+        LimitValue::Number(count) if count.fract() == 0.0 => *count as u64,
+        LimitValue::Number(_) => return None,
+        LimitValue::Text(text) => text.trim().parse().ok()?,
+    };
+    (count > 0).then_some(count)
+}
+
+/// A byte size with an optional binary K/M/G/T suffix, e.g. `1.5G`.
+fn parse_size(text: &str) -> Option<u64> {
+    let (number, scale) = match text.char_indices().last()? {
+        (index, 'k' | 'K') => (&text[..index], 1u64 << 10),
+        (index, 'm' | 'M') => (&text[..index], 1 << 20),
+        (index, 'g' | 'G') => (&text[..index], 1 << 30),
+        (index, 't' | 'T') => (&text[..index], 1 << 40),
+        _ => (text, 1),
+    };
+    let number = number.trim().parse::<f64>().ok()?;
+    if !number.is_finite() || number < 0.0 {
+        return None;
+    }
+    Some((number * scale as f64) as u64)
+}
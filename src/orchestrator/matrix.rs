@@ -0,0 +1,185 @@
+use crate::orchestrator::compose::set_path;
+use crate::orchestrator::config::ConfigError;
+use serde_yaml::{Mapping, Value};
+use std::collections::BTreeMap;
+use std::error::Error;
+
+/// Matrix keys that set a step field instead of an env var.
+const FIELD_KEYS: &[(&str, &[&str])] = &[
+    ("processes", &["parallel", "processes"]),
+    ("threads", &["parallel", "threads"]),
+    ("duration_ms", &["duration_ms"]),
+];
+
+/// One point of a sweep: its parameter values and the config they produce.
+pub(crate) struct Variant {
+    pub(crate) params: BTreeMap<String, String>,
+    pub(crate) config: Value,
+}
+
+struct Axis {
+    /// Step index for a step's `matrix`, `None` for the top-level one.
+    step: Option<usize>,
+    /// Parameter name used to tag runs (`<step-id>.<key>` for step axes).
+    label: String,
+    key: String,
+    values: Vec<Value>,
+}
+
+/// Expands top-level and per-step `matrix` blocks into the cartesian product
+/// of their values, in order with the last axis varying fastest. A config
+/// without a matrix yields one variant with no params.
+pub(crate) fn expand_matrix(mut root: Value) -> Result<Vec<Variant>, Box<dyn Error>> {
+    let mut axes = Vec::new();
+    if let Some(matrix) = root.as_mapping_mut().and_then(|map| map.remove("matrix")) {
+        axes.extend(read_axes(&matrix, None)?);
+    }
+    if let Some(Value::Sequence(steps)) = root.get_mut("steps") {
+        for (index, step) in steps.iter_mut().enumerate() {
+            let Some(map) = step.as_mapping_mut() else {
+                continue;
+            };
+            let Some(matrix) = map.remove("matrix") else {
+                continue;
+            };
+            let id = map
+                .get("id")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .unwrap_or_else(|| index.to_string());
+            axes.extend(read_axes(&matrix, Some((index, &id)))?);
+        }
+    }
+
+    let total = axes.iter().map(|axis| axis.values.len()).product::<usize>();
+    let mut variants = Vec::with_capacity(total);
+    for number in 0..total {
+        let mut config = root.clone();
+        let mut params = BTreeMap::new();
+        let mut remainder = number;
+        let mut picks = vec![0; axes.len()];
+        for (index, axis) in axes.iter().enumerate().rev() {
+            picks[index] = remainder % axis.values.len();
+            remainder /= axis.values.len();
+        }
+        for (axis, pick) in axes.iter().zip(picks) {
+            let value = &axis.values[pick];
+            apply_axis(&mut config, axis, value)?;
+            params.insert(axis.label.clone(), scalar_text(value));
+        }
+        variants.push(Variant { params, config });
+    }
+    Ok(variants)
+}
+
+fn read_axes(matrix: &Value, step: Option<(usize, &str)>) -> Result<Vec<Axis>, Box<dyn Error>> {
+    let owner = match step {
+        Some((_, id)) => format!("step '{}' matrix", id),
+        None => "matrix".to_string(),
+    };
+    let matrix = match matrix {
+        Value::Mapping(matrix) => matrix,
+        _ => {
+            return Err(Box::new(ConfigError(format!(
+                "{} must be a mapping",
+                owner
+            ))));
+        }
+    };
+    let mut axes = Vec::new();
+    for (key, values) in matrix {
+        let key = key
+            .as_str()
+            .ok_or_else(|| ConfigError(format!("{} keys must be strings", owner)))?;
+        let values = match values {
+            Value::Sequence(values) if !values.is_empty() => values.clone(),
+            _ => {
+                return Err(Box::new(ConfigError(format!(
+                    "{} '{}' must be a non-empty list",
+                    owner, key
+                ))));
+            }
+        };
+        if values
+            .iter()
+            .any(|value| !matches!(value, Value::String(_) | Value::Number(_) | Value::Bool(_)))
+        {
+            return Err(Box::new(ConfigError(format!(
+                "{} '{}' values must be strings, numbers or booleans",
+                owner, key
+            ))));
+        }
+        axes.push(Axis {
+            step: step.map(|(index, _)| index),
+            label: match step {
+                Some((_, id)) => format!("{}.{}", id, key),
+                None => key.to_string(),
+            },
+            key: key.to_string(),
+            values,
+        });
+    }
+    Ok(axes)
+}
+
+/// Applies one axis value. A top-level axis reaches every non-observe step and
+/// is also readable as `{var:KEY}`; dotted keys are config paths as with `--set`.
+fn apply_axis(root: &mut Value, axis: &Axis, value: &Value) -> Result<(), Box<dyn Error>> {
+    if let Some(index) = axis.step {
+        let step = root
+            .get_mut("steps")
+            .and_then(|steps| steps.get_mut(index))
+            .ok_or_else(|| ConfigError(format!("matrix step {} not found", index)))?;
+        return apply_to_step(step, &axis.key, value);
+    }
+
+    if axis.key.contains('.') {
+        return set_path(root, &split_path(&axis.key), value.clone(), "matrix");
+    }
+    if let Some(Value::Sequence(steps)) = root.get_mut("steps") {
+        for step in steps
+            .iter_mut()
+            .filter(|step| step.get("observe").is_none())
+        {
+            apply_to_step(step, &axis.key, value)?;
+        }
+    }
+    if !FIELD_KEYS.iter().any(|(key, _)| *key == axis.key) {
+        let vars = root
+            .as_mapping_mut()
+            .ok_or_else(|| ConfigError("config must be a mapping".to_string()))?
+            .entry(Value::from("vars"))
+            .or_insert_with(|| Value::Mapping(Mapping::new()));
+        set_path(
+            vars,
+            std::slice::from_ref(&axis.key),
+            Value::String(scalar_text(value)),
+            "matrix",
+        )?;
+    }
+    Ok(())
+}
+
+/// `processes`, `threads` and `duration_ms` set those fields, dotted keys are
+/// paths inside the step, and any other key is an env var.
+fn apply_to_step(step: &mut Value, key: &str, value: &Value) -> Result<(), Box<dyn Error>> {
+    let path = match FIELD_KEYS.iter().find(|(field, _)| *field == key) {
+        Some((_, path)) => path.iter().map(|part| part.to_string()).collect(),
+        None if key.contains('.') => split_path(key),
+        None => vec!["env".to_string(), key.to_string()],
+    };
+    set_path(step, &path, value.clone(), "matrix")
+}
+
+fn split_path(key: &str) -> Vec<String> {
+    key.split('.').map(str::to_string).collect()
+}
+
+fn scalar_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        Value::Number(number) => number.to_string(),
+        Value::Bool(flag) => flag.to_string(),
+        _ => String::new(),
+    }
+}
@@ -1,20 +1,52 @@
+mod archive;
 mod bin;
 mod cache;
+mod compose;
 mod config;
+mod controls;
+mod credentials;
+mod deps;
+mod git;
 mod golang;
+mod isolate;
+mod limits;
+mod matrix;
 mod node;
+mod observe;
+mod phase;
+mod pipes;
 mod process;
+mod profile;
 mod python;
+mod report;
+mod rlimits;
 mod samples;
+mod sched;
 mod shell;
 mod source;
+mod stats;
+mod supervisor;
 mod templating;
 mod wrapper;
 
-use crate::orchestrator::cache::{CacheContext, cache_context};
-use crate::orchestrator::config::{Config, ConfigError, Dependency, validate_config};
-use crate::orchestrator::templating::apply_placeholders;
-use std::collections::HashMap;
+use crate::orchestrator::cache::{CacheContext, cache_context, sweep_dir};
+use crate::orchestrator::compose::load_composed;
+use crate::orchestrator::config::{
+    Config, ConfigError, Count, Trials, validate_config, validate_refresh,
+};
+use crate::orchestrator::controls::StepControls;
+use crate::orchestrator::credentials::credentials;
+use crate::orchestrator::limits::{finish_removals, prepare_default_parent};
+use crate::orchestrator::matrix::expand_matrix;
+use crate::orchestrator::pipes::pipes;
+use crate::orchestrator::process::PendingProcess;
+use crate::orchestrator::report::{
+    RunReport, SweepPoint, format_params, write_report, write_sweep,
+};
+use crate::orchestrator::source::ensure_source_cached;
+use crate::orchestrator::supervisor::{Supervisor, block_signals};
+use crate::orchestrator::templating::parse_duration_ms;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs;
 use std::path::Path;
@@ -81,34 +113,169 @@ pub fn list_available() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-pub fn generate(config_path: &Path) -> Result<(), Box<dyn Error>> {
-    let loaded = load_config(config_path)?;
-    validate_config(&loaded.config)?;
-    for step in &loaded.config.steps {
-        ensure_runtime_available(&step.runtime)?;
+pub struct GenerateOptions {
+    pub refresh: Option<String>,
+    pub offline: bool,
+    /// `PATH=VALUE` overrides from `--set`.
+    pub overrides: Vec<String>,
+    pub repeat: Option<u32>,
+    pub warmup: Option<u32>,
+    pub cooldown: Option<String>,
+}
+
+pub fn generate(config_path: &Path, options: &GenerateOptions) -> Result<(), Box<dyn Error>> {
+    if let Some(refresh) = &options.refresh {
+        validate_refresh(refresh)?;
+    }
+    let mut composed = load_composed(config_path, &options.overrides)?;
+    let trials = trial_plan(&mut composed, options)?;
+    let sweep_content = serde_yaml::to_string(&composed)?;
+    let mut variants = expand_matrix(composed)?;
+    if variants.len() == 1 && variants[0].params.is_empty() && trials.is_single() {
+        let variant = variants.remove(0);
+        run_config(variant.config, variant.params, options)?;
+        return Ok(());
     }
 
-    let steps = loaded.config.steps;
-    let shared = SharedState::new();
+    let (sweep_id, dir) = sweep_dir(&sweep_content);
+    let total = variants.len();
+    let mut points = Vec::new();
+    let mut first = true;
+    for (index, variant) in variants.into_iter().enumerate() {
+        println!(
+            "sweep: point {}/{} {}",
+            index + 1,
+            total,
+            format_params(&variant.params)
+        );
+        let mut warmup_runs = Vec::new();
+        let mut runs = Vec::new();
+        for trial in 0..trials.warmup + trials.repeat {
+            if !first && trials.cooldown_ms > 0 {
+                println!("trial: cooldown ms={}", trials.cooldown_ms);
+                std::thread::sleep(std::time::Duration::from_millis(trials.cooldown_ms));
+            }
+            first = false;
+            let warmup = trial < trials.warmup;
+            if warmup {
+                println!("trial: warmup {}/{}", trial + 1, trials.warmup);
+            } else {
+                println!("trial: run {}/{}", trial - trials.warmup + 1, trials.repeat);
+            }
+            let report = run_config(variant.config.clone(), variant.params.clone(), options)?;
+            if warmup {
+                warmup_runs.push(report.run_id);
+            } else {
+                runs.push(report);
+            }
+        }
+        points.push(SweepPoint::new(variant.params, warmup_runs, runs));
+    }
+    write_sweep(sweep_id, &dir, points)
+}
 
-    let mut handles = Vec::new();
-    for step in steps {
-        let cache = loaded.cache.clone();
-        let shared = shared.clone();
-        handles.push(std::thread::spawn(move || {
-            run_step_with_deps(step, cache, shared).map_err(|err| err.to_string())
-        }));
+struct TrialPlan {
+    repeat: u32,
+    warmup: u32,
+    cooldown_ms: u64,
+}
+
+impl TrialPlan {
+    fn is_single(&self) -> bool {
+        self.repeat == 1 && self.warmup == 0
     }
+}
 
-    for handle in handles {
-        match handle.join() {
-            Ok(Ok(())) => {}
-            Ok(Err(message)) => return Err(Box::new(ConfigError(message))),
-            Err(_) => return Err(Box::new(ConfigError("worker thread panicked".to_string()))),
+/// Takes the `trials` block out of the config; CLI flags win over it.
+fn trial_plan(
+    composed: &mut serde_yaml::Value,
+    options: &GenerateOptions,
+) -> Result<TrialPlan, Box<dyn Error>> {
+    let trials = match composed
+        .as_mapping_mut()
+        .and_then(|map| map.remove("trials"))
+    {
+        Some(value) => serde_yaml::from_value::<Trials>(value)?,
+        None => Trials::default(),
+    };
+    let repeat = options.repeat.or(trials.repeat).unwrap_or(1);
+    if repeat == 0 {
+        return Err(Box::new(ConfigError("repeat must be > 0".to_string())));
+    }
+    let cooldown = match &options.cooldown {
+        Some(text) => Some(Count::Template(text.clone())),
+        None => trials.cooldown,
+    };
+    let cooldown_ms = match cooldown {
+        None => 0,
+        Some(Count::Value(value)) => value,
+        Some(Count::Template(text)) => parse_duration_ms(text.trim()).ok_or_else(|| {
+            ConfigError(format!(
+                "cooldown must be a duration (500, 250ms, 60s, 5m), got '{}'",
+                text
+            ))
+        })?,
+    };
+    Ok(TrialPlan {
+        repeat,
+        warmup: options.warmup.or(trials.warmup).unwrap_or(0),
+        cooldown_ms,
+    })
+}
+
+/// Runs one fully composed config and writes its run summary.
+fn run_config(
+    value: serde_yaml::Value,
+    params: BTreeMap<String, String>,
+    options: &GenerateOptions,
+) -> Result<RunReport, Box<dyn Error>> {
+    let loaded = load_config(value, options)?;
+    validate_config(&loaded.config)?;
+    for step in &loaded.config.steps {
+        match &step.observe {
+            Some(observe) => ensure_tool_available(observe::tool_binary(&observe.tool))?,
+            None => ensure_runtime_available(&step.runtime)?,
+        }
+        if credentials(step)?.is_some() && !is_cmd_available("setpriv") {
+            return Err(Box::new(ConfigError(
+                "'user', 'group', 'clear_groups' and 'drop_caps' need 'setpriv' (util-linux) on PATH"
+                    .to_string(),
+            )));
         }
+        ensure_source_cached(step, &loaded.cache)?;
     }
 
-    Ok(())
+    // Before the relay threads start, so that they inherit the mask too.
+    let signals = block_signals()?;
+    let steps = loaded.config.steps;
+    prepare_default_parent(&steps);
+    let phases = loaded.config.phases.unwrap_or_default();
+    let fail_fast = loaded.config.fail_fast;
+    let vars = std::sync::Arc::new(loaded.config.vars);
+    let pipes = std::sync::Arc::new(pipes(&steps, &loaded.cache.run_dir)?);
+
+    let supervisor = Supervisor::new(
+        steps,
+        &phases,
+        &loaded.cache,
+        vars,
+        pipes.clone(),
+        &signals,
+        fail_fast,
+    )?;
+    let outcome = supervisor.run();
+    pipes.finish();
+    finish_removals();
+    let outcome = outcome?;
+
+    let report = write_report(&loaded.cache, params, outcome.phases, outcome.steps)?;
+    if let Some(signal) = outcome.interrupted {
+        return Err(Box::new(ConfigError(format!("interrupted by {}", signal))));
+    }
+    if let Some(error) = outcome.error {
+        return Err(Box::new(ConfigError(error)));
+    }
+    Ok(report)
 }
 
 pub fn samples(output_dir: &Path) -> Result<(), Box<dyn Error>> {
@@ -120,12 +287,19 @@ struct LoadedConfig {
     cache: CacheContext,
 }
 
-fn load_config(config_path: &Path) -> Result<LoadedConfig, Box<dyn Error>> {
-    let content = fs::read_to_string(config_path)?;
-    let cache = cache_context(&content);
+fn load_config(
+    value: serde_yaml::Value,
+    options: &GenerateOptions,
+) -> Result<LoadedConfig, Box<dyn Error>> {
+    // Hash the merged config so includes and overrides key the cache too.
+    let content = serde_yaml::to_string(&value)?;
+    let cache = cache_context(&content, options.refresh.clone(), options.offline);
     fs::create_dir_all(&cache.source_dir)?;
     fs::create_dir_all(&cache.url_dir)?;
-    let config: Config = serde_yaml::from_str(&content)?;
+    fs::create_dir_all(&cache.git_dir)?;
+    fs::create_dir_all(&cache.run_dir)?;
+    println!("run: id={} dir={}", cache.run_id, cache.run_dir.display());
+    let config: Config = serde_yaml::from_value(value)?;
     Ok(LoadedConfig { config, cache })
 }
 
@@ -156,6 +330,16 @@ fn ensure_runtime_available(runtime: &str) -> Result<(), Box<dyn Error>> {
     }
 }
 
+fn ensure_tool_available(cmd: &str) -> Result<(), Box<dyn Error>> {
+    if is_cmd_available(cmd) {
+        return Ok(());
+    }
+    Err(Box::new(ConfigError(format!(
+        "observe tool '{}' not found on PATH",
+        cmd
+    ))))
+}
+
 fn is_cmd_available(cmd: &str) -> bool {
     Command::new("which")
         .arg(cmd)
@@ -164,149 +348,35 @@ fn is_cmd_available(cmd: &str) -> bool {
         .unwrap_or(false)
 }
 
-#[derive(Clone)]
-struct SharedState {
-    inner: std::sync::Arc<(std::sync::Mutex<StateMap>, std::sync::Condvar)>,
+fn unix_millis() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
 }
 
-type StateMap = HashMap<String, StepState>;
-
-#[derive(Clone, Default)]
-struct StepState {
-    started: bool,
-    finished: bool,
-    pids: Vec<u32>,
-    exit_codes: Vec<i32>,
-}
-
-impl SharedState {
-    fn new() -> Self {
-        SharedState {
-            inner: std::sync::Arc::new((
-                std::sync::Mutex::new(HashMap::new()),
-                std::sync::Condvar::new(),
-            )),
-        }
-    }
-
-    fn update_started(&self, id: &str, pids: Vec<u32>) {
-        let (lock, cv) = &*self.inner;
-        let mut map = lock.lock().unwrap();
-        let entry = map.entry(id.to_string()).or_default();
-        entry.started = true;
-        entry.pids = pids;
-        cv.notify_all();
-    }
-
-    fn update_finished(&self, id: &str, exit_codes: Vec<i32>) {
-        let (lock, cv) = &*self.inner;
-        let mut map = lock.lock().unwrap();
-        let entry = map.entry(id.to_string()).or_default();
-        entry.finished = true;
-        entry.exit_codes = exit_codes;
-        cv.notify_all();
-    }
-
-    fn snapshot_pids(&self) -> HashMap<String, Vec<u32>> {
-        let (lock, _) = &*self.inner;
-        let map = lock.lock().unwrap();
-        map.iter()
-            .map(|(id, state)| (id.clone(), state.pids.clone()))
-            .collect()
-    }
-
-    fn wait_for(&self, dep: &Dependency) -> Result<(), Box<dyn Error>> {
-        let (lock, cv) = &*self.inner;
-        let mut map = lock.lock().unwrap();
-        loop {
-            let state = map.get(&dep.id);
-            if dependency_satisfied(dep, state) {
-                return Ok(());
-            }
-            map = cv.wait(map).unwrap();
-        }
-    }
-}
-
-fn dependency_satisfied(dep: &Dependency, state: Option<&StepState>) -> bool {
-    let when = dep.when.as_deref().unwrap_or("started");
-    let state = match state {
-        Some(state) => state,
-        None => return false,
-    };
-    match when {
-        "started" => state.started,
-        "exited" => {
-            if !state.finished {
-                return false;
-            }
-            if let Some(codes) = &dep.exit_codes {
-                return state.exit_codes.iter().all(|code| codes.contains(code));
-            }
-            true
-        }
-        _ => false,
-    }
-}
-
-fn run_step_with_deps(
-    step: config::Step,
-    cache: CacheContext,
-    shared: SharedState,
-) -> Result<(), Box<dyn Error>> {
-    if let Some(deps) = &step.depends_on {
-        if step.id.is_none() {
-            return Err(Box::new(ConfigError(
-                "step id is required when using depends_on".to_string(),
-            )));
-        }
-        for dep in deps {
-            shared.wait_for(dep)?;
-        }
-    }
-
-    let pid_map = shared.snapshot_pids();
-    let step = apply_placeholders(&step, &pid_map)?;
-
-    let step_id = step.id.clone();
-    let on_start = |pids: &[u32]| {
-        if let Some(id) = &step_id {
-            shared.update_started(id, pids.to_vec());
-        }
-    };
-
-    let outcome = run_step(step, cache, &on_start)?;
-    if let Some(id) = &step_id {
-        shared.update_finished(id, outcome.exit_codes);
-    }
-    Ok(())
-}
-
-pub(crate) struct StepOutcome {
-    pids: Vec<u32>,
-    exit_codes: Vec<i32>,
-}
-
-fn run_step(
-    step: config::Step,
-    cache: CacheContext,
-    on_start: &dyn Fn(&[u32]),
-) -> Result<StepOutcome, Box<dyn Error>> {
+/// Prepares the processes of a workload step; returns the runtime's name for
+/// log lines and the processes to spawn.
+fn launch_step(
+    step: &config::Step,
+    cache: &CacheContext,
+    controls: &StepControls,
+) -> Result<(&'static str, Vec<PendingProcess>), Box<dyn Error>> {
     let runtime = step.runtime.to_lowercase();
     if runtime == "python" || runtime == "python3" || runtime == "cpython" {
-        return python::run(&step, &cache, on_start);
+        return Ok(("python", python::launch(step, cache, controls)?));
     }
     if runtime == "node" || runtime == "node.js" {
-        return node::run(&step, &cache, on_start);
+        return Ok(("node", node::launch(step, cache, controls)?));
     }
     if runtime == "golang" || runtime == "go" {
-        return golang::run(&step, &cache, on_start);
+        return Ok(("golang", golang::launch(step, cache, controls)?));
     }
     if runtime == "bin" {
-        return bin::run(&step, on_start);
+        return Ok(("bin", bin::launch(step, cache, controls)?));
     }
     if runtime == "shell" {
-        return shell::run(&step, on_start);
+        return Ok(("shell", shell::launch(step, cache, controls)?));
     }
 
     Err(Box::new(ConfigError(format!(
@@ -1,73 +1,62 @@
 use crate::orchestrator::cache::CacheContext;
-use crate::orchestrator::config::{Step, step_duration_ms, step_env, step_processes, step_stdout};
-use crate::orchestrator::process::{kill_process, spawn_process, wait_process};
-use crate::orchestrator::StepOutcome;
+use crate::orchestrator::config::{Step, step_env, step_processes, step_stdout};
+use crate::orchestrator::controls::StepControls;
+use crate::orchestrator::deps::node_modules;
+use crate::orchestrator::process::PendingProcess;
 use crate::orchestrator::source::resolve_source;
+use crate::orchestrator::templating::{ProcessVars, expand_process_tokens};
 use crate::orchestrator::wrapper::wrap_command;
 use std::error::Error;
 use std::path::Path;
-use std::thread;
-use std::time::Duration;
 
-pub(crate) fn run(
+pub(crate) fn launch(
     step: &Step,
     cache: &CacheContext,
-    on_start: &dyn Fn(&[u32]),
-) -> Result<StepOutcome, Box<dyn Error>> {
+    controls: &StepControls,
+) -> Result<Vec<PendingProcess>, Box<dyn Error>> {
     let processes = step_processes(step);
     let stdout_enabled = step_stdout(step);
-    let duration_ms = step_duration_ms(step);
     let source = resolve_source(step, Path::new("runtimes/node/main.js"), "js", cache)?;
     let args = step.args.as_deref().unwrap_or(&[]);
-    let envs = step_env(step);
+    let mut envs = step_env(step);
+    if let Some(modules) = node_modules(step, &envs, cache)? {
+        envs.push((
+            "NODE_PATH".to_string(),
+            modules.to_string_lossy().to_string(),
+        ));
+    }
 
     println!("node: processes={} args={}", processes, args.join(" "));
 
     let step_id = step.id.as_deref().unwrap_or("unknown");
     let log_label = format!("step={} runtime=node", step_id);
 
-    let mut children = Vec::new();
-    let mut pids = Vec::new();
-    for _ in 0..processes {
+    let mut pending = Vec::new();
+    for proc_index in 0..processes {
         let mut base = vec![
             "node".to_string(),
             source.path.to_string_lossy().to_string(),
         ];
         base.extend(args.iter().cloned());
-        let wrapped = wrap_command(step, &base);
+        let vars = ProcessVars {
+            step_id,
+            run_dir: &cache.run_dir,
+            proc_index,
+        };
+        let wrapped = wrap_command(step, &base, &vars)?;
         let mut command = wrapped.command;
+        let settings = controls.prepare(&mut command, &vars)?;
         for (key, value) in &envs {
-            command.env(key, value);
-        }
-        let child = spawn_process(command, &log_label, &wrapped.display, stdout_enabled)?;
-        pids.push(child.pid());
-        children.push(child);
-    }
-
-    on_start(&pids);
-
-    if let Some(duration) = duration_ms {
-        thread::sleep(Duration::from_millis(duration));
-        for child in &mut children {
-            let _ = kill_process(child);
+            command.env(key, expand_process_tokens(value, &vars));
         }
+        pending.push(PendingProcess {
+            command,
+            log_label: log_label.clone(),
+            settings,
+            display: wrapped.display,
+            stdout_enabled,
+        });
     }
 
-    let mut exit_codes = Vec::new();
-    for child in children {
-        let code = wait_process(child, &log_label, duration_ms.is_some())?;
-        exit_codes.push(code);
-    }
-
-    println!("pids={}", join_pids(&pids));
-    println!("node: done");
-
-    Ok(StepOutcome { pids, exit_codes })
-}
-
-fn join_pids(pids: &[u32]) -> String {
-    pids.iter()
-        .map(|pid| pid.to_string())
-        .collect::<Vec<_>>()
-        .join(",")
+    Ok(pending)
 }
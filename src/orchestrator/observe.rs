@@ -0,0 +1,134 @@
+use crate::orchestrator::cache::CacheContext;
+use crate::orchestrator::config::{Observe, Step, step_stdout};
+use crate::orchestrator::process::{PendingProcess, SpawnSettings};
+use crate::orchestrator::wrapper::join_quoted;
+use std::error::Error;
+use std::fs::File;
+use std::process::{Command, Stdio};
+
+/// Binary that must be on PATH for an observe tool.
+pub(crate) fn tool_binary(tool: &str) -> &'static str {
+    match tool {
+        "pidstat" => "pidstat",
+        "strace" => "strace",
+        "perf-record" => "perf",
+        _ => "gdb",
+    }
+}
+
+/// Label of an observer's start and end lines.
+pub(crate) fn log_label(step_id: &str, observe: &Observe) -> String {
+    format!(
+        "step={} observe={} target={}",
+        step_id, observe.tool, observe.target
+    )
+}
+
+/// Prepares the tool to attach to all of the target's PIDs. The supervisor
+/// starts it once the target has started and detaches it (SIGINT) once the
+/// target has exited or the step is asked to stop.
+pub(crate) fn launch(
+    step: &Step,
+    observe: &Observe,
+    cache: &CacheContext,
+    target_pids: &[u32],
+) -> Result<Vec<PendingProcess>, Box<dyn Error>> {
+    let step_id = step.id.as_deref().unwrap_or("unknown");
+    let log_label = log_label(step_id, observe);
+    println!(
+        "observe: tool={} target={} pids={}",
+        observe.tool,
+        observe.target,
+        join_pids(target_pids)
+    );
+
+    let mut pending = Vec::new();
+    for (argv, log_path) in observer_commands(step_id, observe, target_pids, cache) {
+        let mut command = Command::new(&argv[0]);
+        command.args(&argv[1..]);
+        command.stdin(Stdio::null());
+        for (key, value) in step.env.iter().flatten() {
+            command.env(key, value);
+        }
+        let stdout_enabled = step_stdout(step) && log_path.is_none();
+        if let Some(path) = &log_path {
+            command.stdout(Stdio::from(File::create(path)?));
+        }
+        pending.push(PendingProcess {
+            command,
+            log_label: log_label.clone(),
+            settings: SpawnSettings::default(),
+            display: join_quoted(&argv),
+            stdout_enabled,
+        });
+    }
+    Ok(pending)
+}
+
+/// Builds one command per observer process, with an optional stdout log file.
+fn observer_commands(
+    step_id: &str,
+    observe: &Observe,
+    target_pids: &[u32],
+    cache: &CacheContext,
+) -> Vec<(Vec<String>, Option<std::path::PathBuf>)> {
+    let output = |suffix: &str| {
+        cache
+            .run_dir
+            .join(format!("{}.{}", step_id, suffix))
+            .to_string_lossy()
+            .to_string()
+    };
+    let extra = |default: &[&str]| -> Vec<String> {
+        match &observe.args {
+            Some(args) => args.clone(),
+            None => default.iter().map(|arg| arg.to_string()).collect(),
+        }
+    };
+    let pid_list = join_pids(target_pids);
+
+    match observe.tool.as_str() {
+        "pidstat" => {
+            let mut argv = vec!["pidstat".to_string()];
+            argv.extend(extra(&["-u"]));
+            argv.extend(["-p".to_string(), pid_list, "1".to_string()]);
+            vec![(argv, Some(output("pidstat.log").into()))]
+        }
+        "strace" => {
+            let mut argv = vec!["strace".to_string()];
+            argv.extend(extra(&["-f"]));
+            argv.extend(["-o".to_string(), output("strace.txt")]);
+            for pid in target_pids {
+                argv.extend(["-p".to_string(), pid.to_string()]);
+            }
+            vec![(argv, None)]
+        }
+        "perf-record" => {
+            let mut argv = vec!["perf".to_string(), "record".to_string()];
+            argv.extend(extra(&["-g"]));
+            argv.extend([
+                "-o".to_string(),
+                output("perf.data"),
+                "-p".to_string(),
+                pid_list,
+            ]);
+            vec![(argv, None)]
+        }
+        _ => target_pids
+            .iter()
+            .map(|pid| {
+                let mut argv = vec!["gdb".to_string(), "-batch".to_string()];
+                argv.extend(["-p".to_string(), pid.to_string()]);
+                argv.extend(extra(&["-ex", "thread apply all bt"]));
+                (argv, Some(output(&format!("gdb.{}.log", pid)).into()))
+            })
+            .collect(),
+    }
+}
+
+fn join_pids(pids: &[u32]) -> String {
+    pids.iter()
+        .map(|pid| pid.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
@@ -0,0 +1,259 @@
+use crate::orchestrator::config::{Phase, Step, phase_duration_ms};
+use crate::orchestrator::unix_millis;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// Timing of one phase, recorded in the run summary.
+#[derive(Debug, Serialize)]
+pub(crate) struct PhaseReport {
+    pub(crate) name: String,
+    pub(crate) started_at_ms: u128,
+    pub(crate) duration_ms: u128,
+    pub(crate) steps: Vec<String>,
+}
+
+/// Starts each phase once the previous one has ended. A phase ends when its
+/// non-persistent steps have exited or its `duration_ms` elapses, whichever
+/// comes first; steps still running then are stopped. A phase whose steps all
+/// persist ends at its deadline, or once they have started if it has none.
+/// After the last phase every remaining step (persistent or outside any
+/// phase) is stopped.
+pub(crate) struct PhasePlan {
+    phases: Vec<PlannedPhase>,
+    /// Ids of every step, stopped after the last phase.
+    all: Vec<String>,
+    current: Option<CurrentPhase>,
+    /// Index of the phase that has started most recently.
+    started: Option<usize>,
+    reports: Vec<PhaseReport>,
+    done: bool,
+}
+
+struct PlannedPhase {
+    name: String,
+    steps: Vec<String>,
+    /// Members whose exit ends the phase (all but `persist: true`).
+    ending: Vec<String>,
+    duration: Option<Duration>,
+}
+
+struct CurrentPhase {
+    index: usize,
+    started: Instant,
+    started_at_ms: u128,
+    deadline: Option<Instant>,
+    /// Set once the deadline has passed and the ending steps were stopped.
+    stopping: bool,
+}
+
+impl PhasePlan {
+    pub(crate) fn new(phases: &[Phase], steps: &[Step]) -> Self {
+        let planned = phases
+            .iter()
+            .map(|phase| {
+                let members = steps
+                    .iter()
+                    .filter(|step| step.phase.as_deref() == Some(phase.name.as_str()))
+                    .collect::<Vec<_>>();
+                PlannedPhase {
+                    name: phase.name.clone(),
+                    steps: members.iter().filter_map(|step| step.id.clone()).collect(),
+                    ending: members
+                        .iter()
+                        .filter(|step| !step.persist.unwrap_or(false))
+                        .filter_map(|step| step.id.clone())
+                        .collect(),
+                    duration: phase
+                        .duration_ms
+                        .as_ref()
+                        .and_then(phase_duration_ms)
+                        .map(Duration::from_millis),
+                }
+            })
+            .collect::<Vec<_>>();
+        PhasePlan {
+            done: planned.is_empty(),
+            phases: planned,
+            all: steps.iter().filter_map(|step| step.id.clone()).collect(),
+            current: None,
+            started: None,
+            reports: Vec::new(),
+        }
+    }
+
+    /// Index of the phase that has started most recently.
+    pub(crate) fn started(&self) -> Option<usize> {
+        self.started
+    }
+
+    /// When the running phase times out, unless its steps are already stopping.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.current
+            .as_ref()
+            .filter(|current| !current.stopping)
+            .and_then(|current| current.deadline)
+    }
+
+    /// Ends and starts phases as far as `started` and `finished` allow;
+    /// returns the ids of steps to stop.
+    pub(crate) fn advance(
+        &mut self,
+        started: impl Fn(&str) -> bool,
+        finished: impl Fn(&str) -> bool,
+    ) -> Vec<String> {
+        while !self.done {
+            let Some(current) = &mut self.current else {
+                let index = self.started.map_or(0, |index| index + 1);
+                if index == self.phases.len() {
+                    self.done = true;
+                    return self.all.clone();
+                }
+                self.start(index);
+                continue;
+            };
+            let phase = &self.phases[current.index];
+            let ended = match (phase.ending.is_empty(), current.deadline) {
+                (false, _) => phase.ending.iter().all(|id| finished(id)),
+                (true, Some(deadline)) => Instant::now() >= deadline,
+                (true, None) => phase.steps.iter().all(|id| started(id)),
+            };
+            if ended {
+                self.end();
+                continue;
+            }
+            if !current.stopping
+                && current
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                current.stopping = true;
+                return phase.ending.clone();
+            }
+            break;
+        }
+        Vec::new()
+    }
+
+    /// Starts no further phases, e.g. once the run is interrupted.
+    pub(crate) fn abort(&mut self) {
+        self.done = true;
+    }
+
+    /// Timing of every phase that started; one still running is ended now.
+    pub(crate) fn finish(mut self) -> Vec<PhaseReport> {
+        if self.current.is_some() {
+            self.end();
+        }
+        self.reports
+    }
+
+    fn start(&mut self, index: usize) {
+        let phase = &self.phases[index];
+        let started_at_ms = unix_millis();
+        println!(
+            "phase: start name={} index={} ts={} steps={}",
+            phase.name,
+            index,
+            started_at_ms,
+            phase.steps.join(",")
+        );
+        let started = Instant::now();
+        self.current = Some(CurrentPhase {
+            index,
+            started,
+            started_at_ms,
+            deadline: phase.duration.map(|duration| started + duration),
+            stopping: false,
+        });
+        self.started = Some(index);
+    }
+
+    fn end(&mut self) {
+        let Some(current) = self.current.take() else {
+            return;
+        };
+        let phase = &self.phases[current.index];
+        let duration_ms = current.started.elapsed().as_millis();
+        println!(
+            "phase: end name={} index={} ts={} duration_ms={}",
+            phase.name,
+            current.index,
+            unix_millis(),
+            duration_ms
+        );
+        self.reports.push(PhaseReport {
+            name: phase.name.clone(),
+            started_at_ms: current.started_at_ms,
+            duration_ms,
+            steps: phase.steps.clone(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(config: &str) -> PhasePlan {
+        #[derive(serde::Deserialize)]
+        struct Config {
+            phases: Vec<Phase>,
+            steps: Vec<Step>,
+        }
+        let config: Config = serde_yaml::from_str(config).unwrap();
+        PhasePlan::new(&config.phases, &config.steps)
+    }
+
+    #[test]
+    fn persistent_phase_lasts_its_duration() {
+        let mut plan = plan(
+            "
+phases: [{name: noise, duration_ms: 200}, {name: measure}]
+steps:
+  - {id: noise, phase: noise, persist: true}
+  - {id: work, phase: measure}
+",
+        );
+        assert!(plan.advance(|_| true, |_| false).is_empty());
+        assert_eq!(plan.started(), Some(0));
+        assert!(plan.deadline().is_some());
+
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(plan.advance(|_| true, |_| false).is_empty());
+        assert_eq!(plan.started(), Some(1));
+        let reports = plan.finish();
+        assert!(reports[0].duration_ms >= 200);
+    }
+
+    #[test]
+    fn persistent_phase_without_duration_ends_once_started() {
+        let mut plan = plan(
+            "
+phases: [{name: noise}, {name: measure}]
+steps:
+  - {id: noise, phase: noise, persist: true}
+  - {id: work, phase: measure}
+",
+        );
+        plan.advance(|_| false, |_| false);
+        assert_eq!(plan.started(), Some(0));
+        plan.advance(|id| id == "noise", |_| false);
+        assert_eq!(plan.started(), Some(1));
+    }
+
+    #[test]
+    fn phase_ends_when_its_steps_exit() {
+        let mut plan = plan(
+            "
+phases: [{name: one, duration_ms: 60s}, {name: two}]
+steps:
+  - {id: a, phase: one}
+  - {id: b, phase: two}
+",
+        );
+        plan.advance(|_| true, |_| false);
+        assert_eq!(plan.started(), Some(0));
+        plan.advance(|_| true, |id| id == "a");
+        assert_eq!(plan.started(), Some(1));
+    }
+}
@@ -0,0 +1,231 @@
+use crate::orchestrator::config::{Step, pipe_links};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+/// Bytes the relay moves per read.
+const RELAY_CHUNK: usize = 64 * 1024;
+
+/// OS pipes between steps, created before any step starts. A step's
+/// processes get copies of its ends; `wl` closes its own copies once the step
+/// has started (or ended without starting), so readers see EOF when every
+/// writer has exited and writers block while readers fall behind.
+pub(crate) struct Pipes {
+    links: Vec<Link>,
+    relays: Mutex<Vec<JoinHandle<()>>>,
+}
+
+struct Link {
+    producer: String,
+    consumer: String,
+    /// Write end for the producer's processes.
+    write: Mutex<Option<OwnedFd>>,
+    readers: Readers,
+}
+
+enum Readers {
+    /// The consumer's processes read the producer's pipe directly.
+    Direct(Mutex<Option<OwnedFd>>),
+    /// A relay thread copies the stream to the consumer's pipes and a tee.
+    Relayed(Arc<Relay>),
+}
+
+/// Where a relay sends the stream, filled in while the consumer starts.
+struct Relay {
+    outputs: Mutex<RelayOutputs>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct RelayOutputs {
+    /// With `fan_out: one`, the read end all consumer processes share;
+    /// otherwise each gets a pipe of its own.
+    shared: Option<OwnedFd>,
+    pipes: Vec<File>,
+    /// Set once the consumer has started or ended; no more pipes are added.
+    complete: bool,
+}
+
+/// Creates the pipes for every `stdout_to` and `stdin.step` link and starts
+/// the relays of links with `fan_out: all` or `tee`.
+pub(crate) fn pipes(steps: &[Step], run_dir: &Path) -> io::Result<Pipes> {
+    let mut links = Vec::new();
+    let mut relays = Vec::new();
+    for link in pipe_links(steps) {
+        let (read, write) = pipe()?;
+        let readers = if !link.fan_out_all && link.tee.is_none() {
+            Readers::Direct(Mutex::new(Some(read)))
+        } else {
+            let mut outputs = RelayOutputs::default();
+            if !link.fan_out_all {
+                let (shared, output) = pipe()?;
+                outputs.shared = Some(shared);
+                outputs.pipes.push(File::from(output));
+            }
+            let tee = match &link.tee {
+                Some(path) => {
+                    let path = run_dir.join(path);
+                    if let Some(parent) = path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    Some(File::create(path)?)
+                }
+                None => None,
+            };
+            let relay = Arc::new(Relay {
+                outputs: Mutex::new(outputs),
+                ready: Condvar::new(),
+            });
+            let name = format!("from={} to={}", link.producer, link.consumer);
+            let input = File::from(read);
+            let handle = {
+                let relay = relay.clone();
+                std::thread::spawn(move || run_relay(&name, input, &relay, tee))
+            };
+            relays.push(handle);
+            Readers::Relayed(relay)
+        };
+        links.push(Link {
+            producer: link.producer,
+            consumer: link.consumer,
+            write: Mutex::new(Some(write)),
+            readers,
+        });
+    }
+    Ok(Pipes {
+        links,
+        relays: Mutex::new(relays),
+    })
+}
+
+impl Pipes {
+    /// Whether `step_id` reads its stdin from another step.
+    pub(crate) fn reads(&self, step_id: &str) -> bool {
+        self.links.iter().any(|link| link.consumer == step_id)
+    }
+
+    /// A read end for one of `step_id`'s processes.
+    pub(crate) fn stdin(&self, step_id: &str) -> io::Result<Option<OwnedFd>> {
+        let Some(link) = self.links.iter().find(|link| link.consumer == step_id) else {
+            return Ok(None);
+        };
+        match &link.readers {
+            Readers::Direct(read) => copy(read),
+            Readers::Relayed(relay) => {
+                let mut outputs = relay.outputs.lock().unwrap();
+                if outputs.complete {
+                    return Ok(None);
+                }
+                if let Some(shared) = &outputs.shared {
+                    return shared.try_clone().map(Some);
+                }
+                let (read, write) = pipe()?;
+                outputs.pipes.push(File::from(write));
+                Ok(Some(read))
+            }
+        }
+    }
+
+    /// A copy of the write end `step_id`'s stdout goes to.
+    pub(crate) fn stdout(&self, step_id: &str) -> io::Result<Option<OwnedFd>> {
+        match self.links.iter().find(|link| link.producer == step_id) {
+            Some(link) => copy(&link.write),
+            None => Ok(None),
+        }
+    }
+
+    /// Closes `wl`'s copies of `step_id`'s ends; its processes keep theirs.
+    pub(crate) fn release(&self, step_id: &str) {
+        for link in &self.links {
+            if link.producer == step_id {
+                link.write.lock().unwrap().take();
+            }
+            if link.consumer != step_id {
+                continue;
+            }
+            match &link.readers {
+                Readers::Direct(read) => {
+                    read.lock().unwrap().take();
+                }
+                Readers::Relayed(relay) => {
+                    let mut outputs = relay.outputs.lock().unwrap();
+                    outputs.shared = None;
+                    outputs.complete = true;
+                    relay.ready.notify_all();
+                }
+            }
+        }
+    }
+
+    /// Closes every end `wl` still holds, so that relays of steps that never
+    /// got released (e.g. after a supervisor error) do not wait forever, then
+    /// waits for the relays to drain.
+    pub(crate) fn finish(&self) {
+        for link in &self.links {
+            self.release(&link.producer);
+            self.release(&link.consumer);
+        }
+        for handle in self.relays.lock().unwrap().drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Copies the producer's stream to every consumer pipe and the tee file
+/// until the producer closes it or no consumer is left; the producer then
+/// gets EPIPE, as with a shell pipeline.
+fn run_relay(name: &str, mut input: File, relay: &Relay, mut tee: Option<File>) {
+    let mut outputs = {
+        let outputs = relay.outputs.lock().unwrap();
+        let mut outputs = relay
+            .ready
+            .wait_while(outputs, |outputs| !outputs.complete)
+            .unwrap();
+        std::mem::take(&mut outputs.pipes)
+    };
+
+    let mut buf = vec![0; RELAY_CHUNK];
+    let mut bytes = 0u64;
+    let mut started = None;
+    while !outputs.is_empty() {
+        let count = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        started.get_or_insert_with(Instant::now);
+        bytes += count as u64;
+        if let Some(file) = &mut tee
+            && let Err(err) = file.write_all(&buf[..count])
+        {
+            println!("pipe: {} tee stopped: {}", name, err);
+            tee = None;
+        }
+        outputs.retain_mut(|output| output.write_all(&buf[..count]).is_ok());
+    }
+    let duration_ms = started.map_or(0, |started| started.elapsed().as_millis());
+    println!("pipe: {} bytes={} duration_ms={}", name, bytes, duration_ms);
+}
+
+fn copy(end: &Mutex<Option<OwnedFd>>) -> io::Result<Option<OwnedFd>> {
+    end.lock()
+        .unwrap()
+        .as_ref()
+        .map(OwnedFd::try_clone)
+        .transpose()
+}
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` is a live array with room for both descriptors.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: pipe2 just opened both descriptors and nothing else owns them.
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
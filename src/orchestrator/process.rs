@@ -1,99 +1,403 @@
 use std::error::Error;
-use std::io::{self, BufRead, BufReader};
-use std::process::{Child, Command, Stdio};
-use std::thread::JoinHandle;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
 use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
+/// Bytes read from a child's stdout per read.
+const STDOUT_CHUNK: usize = 8192;
+/// Reads from one stdout per wakeup, so a chatty child cannot starve the
+/// supervisor.
+const STDOUT_READS: usize = 4;
+
 pub(crate) struct ChildTracker {
     child: Child,
     started_at: Instant,
+    /// The process `wl` spawned and reaps. For isolated steps it is a shim
+    /// and the workload, whose host PID is `pid`, its child.
+    wait_pid: u32,
     pid: u32,
-    stdout: Option<JoinHandle<io::Result<()>>>,
+    /// Readable once `wait_pid` exits; `None` without pidfd_open (Linux < 5.3).
+    pidfd: Option<OwnedFd>,
+    stdout: Option<OutputStream>,
+    settings: SpawnSettings,
+    /// OOM kills in the step's cgroup when the process started.
+    oom_kills: Option<u64>,
+    /// Set once `wl` itself signals the process.
+    signaled: bool,
 }
 
 impl ChildTracker {
     pub(crate) fn pid(&self) -> u32 {
         self.pid
     }
+
+    /// Descriptor to poll for the process's exit; without one, poll
+    /// `has_exited` instead.
+    pub(crate) fn exit_fd(&self) -> Option<RawFd> {
+        self.pidfd.as_ref().map(AsRawFd::as_raw_fd)
+    }
+
+    /// The piped stdout while it is open.
+    pub(crate) fn stdout_fd(&self) -> Option<RawFd> {
+        self.stdout.as_ref().map(|stdout| stdout.file.as_raw_fd())
+    }
+
+    /// Prints the stdout lines available so far; closes stdout at EOF.
+    pub(crate) fn read_stdout(&mut self) {
+        if let Some(stdout) = &mut self.stdout
+            && !stdout.read()
+        {
+            self.stdout = None;
+        }
+    }
+
+    /// Prints what is left on stdout and closes it, for a process `wl` has
+    /// stopped whose orphaned descendants may hold stdout open.
+    pub(crate) fn close_stdout(&mut self) {
+        if let Some(mut stdout) = self.stdout.take()
+            && stdout.read()
+        {
+            stdout.flush();
+        }
+    }
+
+    /// Checks for exit without reaping, so `wait_process` still gets the rusage.
+    pub(crate) fn has_exited(&self) -> bool {
+        // SAFETY: siginfo_t is plain old data; all-zero is a valid value.
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        // SAFETY: `info` is a live local; WNOWAIT leaves the child waitable.
+        let result = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                self.wait_pid as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+            )
+        };
+        // SAFETY: si_pid is set by waitid (zero when the child is still running).
+        result != 0 || unsafe { info.si_pid() } != 0
+    }
+}
+
+/// A child's piped stdout, printed line by line with a prefix as it arrives.
+struct OutputStream {
+    file: File,
+    prefix: String,
+    /// Bytes after the last newline read so far.
+    pending: Vec<u8>,
+}
+
+impl OutputStream {
+    /// Prints every complete line read; returns false at EOF, after printing
+    /// an unterminated last line.
+    fn read(&mut self) -> bool {
+        let mut buf = [0; STDOUT_CHUNK];
+        let mut open = true;
+        for _ in 0..STDOUT_READS {
+            match self.file.read(&mut buf) {
+                Ok(0) => open = false,
+                Ok(count) => {
+                    self.pending.extend_from_slice(&buf[..count]);
+                    continue;
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => open = false,
+            }
+            break;
+        }
+        while let Some(end) = self.pending.iter().position(|byte| *byte == b'\n') {
+            let line = self.pending.drain(..=end).collect::<Vec<_>>();
+            self.print(&line[..end]);
+        }
+        if !open {
+            self.flush();
+        }
+        open
+    }
+
+    /// Prints an unterminated last line.
+    fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            let line = std::mem::take(&mut self.pending);
+            self.print(&line);
+        }
+    }
+
+    fn print(&self, line: &[u8]) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        println!("{} {}", self.prefix, String::from_utf8_lossy(line));
+    }
+}
+
+/// Per-process settings made at spawn time, shown on the start line and used
+/// to explain how the process ended.
+#[derive(Default)]
+pub(crate) struct SpawnSettings {
+    /// ` key=value` pairs for the start line.
+    pub(crate) fields: String,
+    /// Soft `RLIMIT_CPU` in seconds.
+    pub(crate) cpu_limit_secs: Option<u64>,
+    /// Whether an address-space rlimit or cgroup memory limit applies.
+    pub(crate) memory_limited: bool,
+    /// `memory.events` of the step's cgroup, whose `oom_kill` count tells an
+    /// OOM kill apart from any other SIGKILL.
+    pub(crate) memory_events: Option<PathBuf>,
+    /// Pipe an isolated process's shim writes the workload's host PID to.
+    pub(crate) host_pid: Option<OwnedFd>,
+}
+
+/// A process a runtime has prepared, spawned later by the supervisor. Its
+/// thread outlives the process, which matters for isolated steps: their shim
+/// dies with the thread that spawned it.
+pub(crate) struct PendingProcess {
+    pub(crate) command: Command,
+    pub(crate) log_label: String,
+    pub(crate) settings: SpawnSettings,
+    pub(crate) display: String,
+    pub(crate) stdout_enabled: bool,
+}
+
+/// How a process ended and what it used, including its reaped descendants.
+pub(crate) struct ProcessExit {
+    pub(crate) code: i32,
+    /// Why the process ended when the exit code alone does not say, e.g.
+    /// `oom-killed`, `cpu-limit` or `SIGSEGV`.
+    pub(crate) reason: Option<String>,
+    /// User plus system CPU time.
+    pub(crate) cpu_ms: u64,
+    pub(crate) max_rss_kb: u64,
+}
+
+impl ProcessExit {
+    /// The reason, or the exit code when there is none.
+    pub(crate) fn label(&self) -> String {
+        self.reason.clone().unwrap_or_else(|| self.code.to_string())
+    }
 }
 
 pub(crate) fn spawn_process(
     mut command: Command,
     log_label: &str,
+    mut settings: SpawnSettings,
     cmd_display: &str,
     stdout_enabled: bool,
 ) -> Result<ChildTracker, Box<dyn Error>> {
     if stdout_enabled {
         command.stdout(Stdio::piped());
     }
+    let oom_kills = settings.memory_events.as_deref().and_then(oom_kill_count);
     let mut child = command.spawn()?;
-    let pid = child.id();
+    let wait_pid = child.id();
+    // Dropping the command closes its copy of the host PID pipe's write end.
+    drop(command);
+    let pid = match settings.host_pid.take() {
+        Some(pipe) => {
+            let mut bytes = [0; 4];
+            if let Err(err) = File::from(pipe).read_exact(&mut bytes) {
+                // The shim is already running; do not leave it behind.
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(Box::new(err));
+            }
+            u32::from_ne_bytes(bytes)
+        }
+        None => wait_pid,
+    };
 
     let started_at = Instant::now();
     let ts = unix_millis();
     println!(
-        "start pid={} ts={} {} cmd=\"{}\"",
-        pid, ts, log_label, cmd_display
+        "start pid={} ts={} {}{} cmd=\"{}\"",
+        pid, ts, log_label, settings.fields, cmd_display
     );
 
-    let stdout = if stdout_enabled {
-        let handle = child.stdout.take().map(|stdout| {
-            let prefix = format!("[pid={} {}]", pid, log_label);
-            std::thread::spawn(move || stream_stdout(stdout, &prefix))
-        });
-        handle
-    } else {
-        None
+    let stdout = match child.stdout.take() {
+        Some(stdout) if stdout_enabled => {
+            let file = File::from(OwnedFd::from(stdout));
+            set_nonblocking(&file)?;
+            Some(OutputStream {
+                file,
+                prefix: format!("[pid={} {}]", pid, log_label),
+                pending: Vec::new(),
+            })
+        }
+        _ => None,
     };
 
     Ok(ChildTracker {
         child,
         started_at,
+        wait_pid,
         pid,
+        pidfd: pidfd_open(wait_pid),
         stdout,
+        settings,
+        oom_kills,
+        signaled: false,
     })
 }
 
-pub(crate) fn wait_process(
-    mut tracker: ChildTracker,
-    log_label: &str,
-    allow_failure: bool,
-) -> Result<i32, Box<dyn Error>> {
-    let status = tracker.child.wait()?;
+/// Reaps an exited process and logs its end line.
+pub(crate) fn wait_process(tracker: &mut ChildTracker, log_label: &str) -> io::Result<ProcessExit> {
+    let (status, usage) = wait_with_usage(tracker.wait_pid)?;
     let duration_ms = tracker.started_at.elapsed().as_millis();
     let ts = unix_millis();
     let exit_code = status.code().unwrap_or(-1);
-    let code_label = if exit_code == -1 {
-        "signal".to_string()
-    } else {
-        exit_code.to_string()
-    };
+    let cpu_ms = timeval_ms(&usage.ru_utime) + timeval_ms(&usage.ru_stime);
+    let max_rss_kb = u64::try_from(usage.ru_maxrss).unwrap_or(0);
+    let reason = exit_reason(tracker, status, cpu_ms);
+    let code_label = reason.clone().unwrap_or_else(|| exit_code.to_string());
     println!(
         "end pid={} ts={} {} duration_ms={} exit={}",
         tracker.pid, ts, log_label, duration_ms, code_label
     );
-    if let Some(handle) = tracker.stdout.take() {
-        let _ = handle.join();
+    Ok(ProcessExit {
+        code: exit_code,
+        reason,
+        cpu_ms,
+        max_rss_kb,
+    })
+}
+
+/// Reaps `pid` with wait4(2) so its rusage comes back with the exit status.
+fn wait_with_usage(pid: u32) -> io::Result<(ExitStatus, libc::rusage)> {
+    let mut status = 0;
+    // SAFETY: rusage is plain old data; all-zero is a valid value.
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        // SAFETY: both pointers refer to live locals; the pid is our own child.
+        let result = unsafe { libc::wait4(pid as libc::pid_t, &mut status, 0, &mut usage) };
+        if result >= 0 {
+            return Ok((ExitStatus::from_raw(status), usage));
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Names how a process ended when its exit code would hide it: a resource
+/// limit, the OOM killer, or the signal that terminated it.
+fn exit_reason(tracker: &ChildTracker, status: ExitStatus, cpu_ms: u64) -> Option<String> {
+    if let Some(code) = status.code() {
+        return (code == libc::ENOMEM && tracker.settings.memory_limited)
+            .then(|| "enomem".to_string());
     }
-    if !allow_failure && !status.success() {
-        return Err(Box::new(io::Error::new(
-            io::ErrorKind::Other,
-            format!("process pid {} exited with {}", tracker.pid, code_label),
-        )));
+    let signal = status.signal()?;
+    let external = signal == libc::SIGKILL && !tracker.signaled;
+    let reason = match signal {
+        libc::SIGXCPU => "cpu-limit",
+        libc::SIGXFSZ => "file-size-limit",
+        _ if external
+            && tracker
+                .settings
+                .cpu_limit_secs
+                .is_some_and(|secs| cpu_ms >= secs * 1000) =>
+        {
+            "cpu-limit"
+        }
+        _ if external
+            && tracker
+                .settings
+                .memory_events
+                .as_deref()
+                .and_then(oom_kill_count)
+                .zip(tracker.oom_kills)
+                .is_some_and(|(now, before)| now > before) =>
+        {
+            "oom-killed"
+        }
+        _ if external => "killed",
+        _ => return Some(signal_name(signal)),
+    };
+    Some(reason.to_string())
+}
+
+/// The `oom_kill` counter of a cgroup's `memory.events` (Linux 4.13+).
+fn oom_kill_count(memory_events: &Path) -> Option<u64> {
+    let events = std::fs::read_to_string(memory_events).ok()?;
+    events
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|value| value.trim().parse().ok())
+}
+
+fn signal_name(signal: i32) -> String {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGSYS => "SIGSYS",
+        _ => return format!("signal-{}", signal),
+    };
+    name.to_string()
+}
+
+fn timeval_ms(value: &libc::timeval) -> u64 {
+    let ms = value.tv_sec * 1000 + value.tv_usec / 1000;
+    u64::try_from(ms).unwrap_or(0)
+}
+
+/// A descriptor that becomes readable when `pid` exits, or `None` when the
+/// kernel lacks pidfd_open.
+fn pidfd_open(pid: u32) -> Option<OwnedFd> {
+    // SAFETY: pidfd_open takes integers and returns a new descriptor or -1.
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    // SAFETY: a non-negative result is a descriptor nothing else owns.
+    (fd >= 0).then(|| unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+fn set_nonblocking(file: &File) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    // SAFETY: fcntl only takes integers here.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    // SAFETY: as above.
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
     }
-    Ok(exit_code)
+    Ok(())
 }
 
 pub(crate) fn kill_process(tracker: &mut ChildTracker) -> Result<(), Box<dyn Error>> {
+    tracker.signaled = true;
+    // An isolated workload is PID 1 of its namespace, so this stops all of
+    // it. The shim reaps the workload just before exiting, so the pid is
+    // still the workload's while the shim runs.
+    if tracker.pid != tracker.wait_pid && !tracker.has_exited() {
+        // SAFETY: kill(2) has no memory-safety requirements.
+        unsafe { libc::kill(tracker.pid as libc::pid_t, libc::SIGKILL) };
+    }
     tracker.child.kill()?;
     Ok(())
 }
 
-fn stream_stdout(stdout: impl std::io::Read, prefix: &str) -> io::Result<()> {
-    let reader = BufReader::new(stdout);
-    for line in reader.lines() {
-        let line = line?;
-        println!("{} {}", prefix, line);
+/// Sends SIGINT so tracers and profilers detach and flush their output.
+/// An exited child stays a zombie until `wait_process` reaps it, so the pid
+/// cannot be reused in between.
+pub(crate) fn interrupt_process(tracker: &mut ChildTracker) -> Result<(), Box<dyn Error>> {
+    tracker.signaled = true;
+    // SAFETY: kill(2) has no memory-safety requirements; the pid is our own child.
+    let result = unsafe { libc::kill(tracker.pid as libc::pid_t, libc::SIGINT) };
+    if result != 0 {
+        return Err(Box::new(io::Error::last_os_error()));
     }
     Ok(())
 }
@@ -0,0 +1,332 @@
+use crate::orchestrator::config::{ConfigError, WrapperArgs, WrapperOptions, WrapperSpec};
+use serde::Serialize;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+pub(crate) const PROFILES: &[&str] = &["perf-stat", "perf-record", "strace-summary", "ltrace"];
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ProfileReport {
+    pub(crate) proc_index: u32,
+    pub(crate) profile: String,
+    pub(crate) output: PathBuf,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) counters: Vec<PerfCounter>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) calls: Vec<CallRow>,
+}
+
+/// One `perf stat` counter; `value` is `None` when not counted or supported.
+#[derive(Debug, Serialize)]
+pub(crate) struct PerfCounter {
+    pub(crate) event: String,
+    pub(crate) value: Option<f64>,
+    pub(crate) unit: String,
+}
+
+/// One row of a `strace -c` / `ltrace -c` summary table.
+#[derive(Debug, Serialize)]
+pub(crate) struct CallRow {
+    pub(crate) name: String,
+    pub(crate) percent: f64,
+    pub(crate) seconds: f64,
+    pub(crate) calls: u64,
+    pub(crate) errors: u64,
+}
+
+/// Builds the wrapper for a preset. Output goes to
+/// `{run_dir}/{step_id}.{proc_index}.<suffix>` so processes don't collide.
+pub(crate) fn profile_wrapper(profile: &str) -> Result<WrapperSpec, Box<dyn Error>> {
+    let output = format!(
+        "{{run_dir}}/{{step_id}}.{{proc_index}}.{}",
+        output_suffix(profile)?
+    );
+    let args: Vec<&str> = match profile {
+        "perf-stat" => vec!["perf", "stat", "-x", ",", "-o", &output],
+        "perf-record" => vec!["perf", "record", "-g", "-o", &output],
+        "strace-summary" => vec!["strace", "-f", "-c", "-o", &output],
+        _ => vec!["ltrace", "-f", "-c", "-o", &output],
+    };
+    Ok(WrapperSpec::Detailed(WrapperOptions {
+        command: WrapperArgs::Args(args.into_iter().map(String::from).collect()),
+        append: None,
+    }))
+}
+
+/// Reads and parses each process's profiler output after the step finished.
+pub(crate) fn collect_profiles(
+    profile: &str,
+    step_id: &str,
+    run_dir: &Path,
+    processes: u32,
+) -> Result<Vec<ProfileReport>, Box<dyn Error>> {
+    let suffix = output_suffix(profile)?;
+    let mut reports = Vec::new();
+    for proc_index in 0..processes {
+        let output = run_dir.join(format!("{}.{}.{}", step_id, proc_index, suffix));
+        let content = std::fs::read_to_string(&output).unwrap_or_default();
+        let (counters, calls) = match profile {
+            "perf-stat" => (parse_perf_stat(&content), Vec::new()),
+            "strace-summary" | "ltrace" => (Vec::new(), parse_call_table(&content)),
+            _ => (Vec::new(), Vec::new()),
+        };
+        reports.push(ProfileReport {
+            proc_index,
+            profile: profile.to_string(),
+            output,
+            counters,
+            calls,
+        });
+    }
+    Ok(reports)
+}
+
+fn output_suffix(profile: &str) -> Result<&'static str, Box<dyn Error>> {
+    match profile {
+        "perf-stat" => Ok("perf-stat.csv"),
+        "perf-record" => Ok("perf.data"),
+        "strace-summary" => Ok("strace.txt"),
+        "ltrace" => Ok("ltrace.txt"),
+        _ => Err(Box::new(ConfigError(format!(
+            "profile must be one of {} (got '{}')",
+            PROFILES.join(", "),
+            profile
+        )))),
+    }
+}
+
+/// Parses `perf stat -x ,` output: `value,unit,event,...` per line.
+fn parse_perf_stat(content: &str) -> Vec<PerfCounter> {
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let fields = line.split(',').collect::<Vec<_>>();
+            if fields.len() < 3 || fields[2].is_empty() {
+                return None;
+            }
+            Some(PerfCounter {
+                event: fields[2].to_string(),
+                value: fields[0].trim().parse().ok(),
+                unit: fields[1].to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Parses the `-c` summary table printed by strace and ltrace. Column spans
+/// are taken from the dashed rule under the header, since cells such as
+/// `errors` may be blank.
+fn parse_call_table(content: &str) -> Vec<CallRow> {
+    let lines = content.lines().collect::<Vec<_>>();
+    let rule = match lines.iter().position(|line| line.starts_with("------")) {
+        Some(rule) if rule > 0 => rule,
+        _ => return Vec::new(),
+    };
+    let spans = column_spans(lines[rule]);
+    let headers = spans
+        .iter()
+        .map(|span| cell(lines[rule - 1], span).to_string())
+        .collect::<Vec<_>>();
+    let column = |name: &str| headers.iter().position(|header| header == name);
+    let (percent, seconds, calls, errors) = (
+        column("% time"),
+        column("seconds"),
+        column("calls"),
+        column("errors"),
+    );
+
+    let mut rows = Vec::new();
+    for line in &lines[rule + 1..] {
+        if line.starts_with("------") || line.trim().is_empty() {
+            continue;
+        }
+        let cells = spans
+            .iter()
+            .map(|span| cell(line, span))
+            .collect::<Vec<_>>();
+        let name = line.split_whitespace().last().unwrap_or("").to_string();
+        let number = |index: Option<usize>| index.and_then(|i| cells.get(i)).copied();
+        rows.push(CallRow {
+            name,
+            percent: number(percent).and_then(|v| v.parse().ok()).unwrap_or(0.0),
+            seconds: number(seconds).and_then(|v| v.parse().ok()).unwrap_or(0.0),
+            calls: number(calls).and_then(|v| v.parse().ok()).unwrap_or(0),
+            errors: number(errors).and_then(|v| v.parse().ok()).unwrap_or(0),
+        });
+    }
+    rows
+}
+
+fn column_spans(rule: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = None;
+    for (index, ch) in rule.char_indices() {
+        match (ch, start) {
+            ('-', None) => start = Some(index),
+            (' ', Some(begin)) => {
+                spans.push((begin, index));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(begin) = start {
+        spans.push((begin, usize::MAX));
+    }
+    spans
+}
+
+fn cell<'a>(line: &'a str, span: &(usize, usize)) -> &'a str {
+    let start = span.0.min(line.len());
+    let end = span.1.min(line.len());
+    line.get(start..end).unwrap_or("").trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orchestrator::config::Step;
+    use crate::orchestrator::templating::ProcessVars;
+    use crate::orchestrator::wrapper::wrap_command;
+
+    /// The argv process `proc_index` of a step `s` running `work` gets.
+    fn argv(step: &str, proc_index: u32) -> Vec<String> {
+        let step: Step = serde_yaml::from_str(step).unwrap();
+        let vars = ProcessVars {
+            step_id: "s",
+            run_dir: Path::new("/run"),
+            proc_index,
+        };
+        let base = ["sh", "-lc", "work"].map(String::from);
+        let command = wrap_command(&step, &base, &vars).unwrap().command;
+        std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn presets_write_per_process_output() {
+        assert_eq!(
+            argv("profile: perf-stat", 1),
+            [
+                "perf",
+                "stat",
+                "-x",
+                ",",
+                "-o",
+                "/run/s.1.perf-stat.csv",
+                "--",
+                "sh",
+                "-lc",
+                "work"
+            ]
+        );
+        assert_eq!(
+            argv("profile: perf-record", 0),
+            [
+                "perf",
+                "record",
+                "-g",
+                "-o",
+                "/run/s.0.perf.data",
+                "--",
+                "sh",
+                "-lc",
+                "work"
+            ]
+        );
+        assert_eq!(
+            argv("profile: strace-summary", 2),
+            [
+                "strace",
+                "-f",
+                "-c",
+                "-o",
+                "/run/s.2.strace.txt",
+                "--",
+                "sh",
+                "-lc",
+                "work"
+            ]
+        );
+        assert_eq!(
+            argv("profile: ltrace", 0),
+            [
+                "ltrace",
+                "-f",
+                "-c",
+                "-o",
+                "/run/s.0.ltrace.txt",
+                "--",
+                "sh",
+                "-lc",
+                "work"
+            ]
+        );
+    }
+
+    #[test]
+    fn profiler_sits_inside_user_wrappers() {
+        assert_eq!(
+            argv("{ profile: strace-summary, wrapper: 'timeout 5' }", 0),
+            [
+                "timeout",
+                "5",
+                "--",
+                "strace",
+                "-f",
+                "-c",
+                "-o",
+                "/run/s.0.strace.txt",
+                "--",
+                "sh",
+                "-lc",
+                "work"
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_presets_are_rejected() {
+        let err = profile_wrapper("valgrind").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "profile must be one of perf-stat, perf-record, strace-summary, ltrace (got 'valgrind')"
+        );
+    }
+
+    #[test]
+    fn perf_stat_csv_is_parsed() {
+        let content = "# started on Mon\n\n1234.5,msec,task-clock,1234,100.00,,\n<not counted>,,cycles,0,0.00,,\n";
+        let counters = parse_perf_stat(content);
+        assert_eq!(counters.len(), 2);
+        assert_eq!(counters[0].event, "task-clock");
+        assert_eq!(counters[0].value, Some(1234.5));
+        assert_eq!(counters[0].unit, "msec");
+        assert_eq!(counters[1].event, "cycles");
+        assert_eq!(counters[1].value, None);
+    }
+
+    #[test]
+    fn call_tables_allow_blank_cells() {
+        let content = "\
+% time     seconds  usecs/call     calls    errors syscall
+------ ----------- ----------- --------- --------- ----------------
+ 60.00    0.000600          30        20           read
+ 40.00    0.000400          20        20         3 openat
+------ ----------- ----------- --------- --------- ----------------
+100.00    0.001000          25        40         3 total
+";
+        let rows = parse_call_table(content);
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].name, "read");
+        assert_eq!(rows[0].calls, 20);
+        assert_eq!(rows[0].errors, 0);
+        assert_eq!(rows[1].name, "openat");
+        assert_eq!(rows[1].errors, 3);
+        assert_eq!(rows[2].name, "total");
+        assert_eq!(rows[2].seconds, 0.001);
+    }
+}
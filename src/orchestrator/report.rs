@@ -0,0 +1,232 @@
+use crate::orchestrator::cache::CacheContext;
+use crate::orchestrator::limits::CgroupReport;
+use crate::orchestrator::phase::PhaseReport;
+use crate::orchestrator::profile::ProfileReport;
+use crate::orchestrator::stats::{StepStats, aggregate};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+
+/// Run summary written to `summary.json` in the run directory.
+#[derive(Debug, Serialize)]
+pub(crate) struct RunReport {
+    pub(crate) run_id: String,
+    pub(crate) config_hash: String,
+    /// Matrix values this run was started with.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) params: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) phases: Vec<PhaseReport>,
+    pub(crate) steps: Vec<StepReport>,
+}
+
+/// Combined summary of a sweep or repeated trials, written to `sweep.json`.
+#[derive(Debug, Serialize)]
+pub(crate) struct SweepReport {
+    pub(crate) sweep_id: String,
+    pub(crate) points: Vec<SweepPoint>,
+}
+
+/// One set of matrix values with its warmup and measured runs.
+#[derive(Debug, Serialize)]
+pub(crate) struct SweepPoint {
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) params: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) warmup_runs: Vec<String>,
+    pub(crate) runs: Vec<RunReport>,
+    /// Per-step aggregates, present when there is more than one measured run.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) stats: Vec<StepStats>,
+}
+
+impl SweepPoint {
+    pub(crate) fn new(
+        params: BTreeMap<String, String>,
+        warmup_runs: Vec<String>,
+        runs: Vec<RunReport>,
+    ) -> Self {
+        let stats = if runs.len() > 1 {
+            aggregate(&runs)
+        } else {
+            Vec::new()
+        };
+        SweepPoint {
+            params,
+            warmup_runs,
+            runs,
+            stats,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct StepReport {
+    pub(crate) id: String,
+    pub(crate) runtime: String,
+    pub(crate) started_at_ms: u128,
+    pub(crate) duration_ms: u128,
+    pub(crate) pids: Vec<u32>,
+    pub(crate) exit_codes: Vec<i32>,
+    /// Per-process exit reasons (`oom-killed`, `cpu-limit`, `SIGSEGV`, ...),
+    /// or the exit code for processes that exited normally; present when
+    /// any process needs one.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) exit_reasons: Vec<String>,
+    /// CPU time summed over the step's processes.
+    pub(crate) cpu_ms: u64,
+    /// Largest peak RSS among the step's processes.
+    pub(crate) max_rss_kb: u64,
+    /// Counters of the step's cgroup when `limits` created one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cgroup: Option<CgroupReport>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) profiles: Vec<ProfileReport>,
+}
+
+pub(crate) fn write_report(
+    cache: &CacheContext,
+    params: BTreeMap<String, String>,
+    phases: Vec<PhaseReport>,
+    steps: Vec<StepReport>,
+) -> Result<RunReport, Box<dyn Error>> {
+    let report = RunReport {
+        run_id: cache.run_id.clone(),
+        config_hash: cache.config_hash.clone(),
+        params,
+        phases,
+        steps,
+    };
+    for phase in &report.phases {
+        println!(
+            "summary: phase={} started_at_ms={} duration_ms={} steps={}",
+            phase.name,
+            phase.started_at_ms,
+            phase.duration_ms,
+            phase.steps.join(",")
+        );
+    }
+    for step in &report.steps {
+        print_step(step);
+    }
+    let path = cache.run_dir.join("summary.json");
+    std::fs::write(&path, serde_json::to_vec_pretty(&report)?)?;
+    println!("summary: report={}", path.display());
+    Ok(report)
+}
+
+pub(crate) fn write_sweep(
+    sweep_id: String,
+    dir: &Path,
+    points: Vec<SweepPoint>,
+) -> Result<(), Box<dyn Error>> {
+    let report = SweepReport { sweep_id, points };
+    for point in &report.points {
+        let params = format_params(&point.params);
+        for run in &point.runs {
+            for step in &run.steps {
+                println!(
+                    "sweep: {}run={} step={} duration_ms={} cpu_ms={} max_rss_kb={} exit={}",
+                    prefix(&params),
+                    run.run_id,
+                    step.id,
+                    step.duration_ms,
+                    step.cpu_ms,
+                    step.max_rss_kb,
+                    format_exits(step)
+                );
+            }
+        }
+        for step in &point.stats {
+            for (name, stats) in &step.metrics {
+                println!(
+                    "stats: {}step={} metric={} n={} mean={:.2} stddev={:.2} min={:.2} p50={:.2} p90={:.2} p99={:.2} max={:.2}",
+                    prefix(&params),
+                    step.id,
+                    name,
+                    stats.n,
+                    stats.mean,
+                    stats.stddev,
+                    stats.min,
+                    stats.p50,
+                    stats.p90,
+                    stats.p99,
+                    stats.max
+                );
+            }
+        }
+    }
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join("sweep.json");
+    std::fs::write(&path, serde_json::to_vec_pretty(&report)?)?;
+    println!("sweep: report={}", path.display());
+    Ok(())
+}
+
+fn prefix(params: &str) -> String {
+    if params.is_empty() {
+        String::new()
+    } else {
+        format!("{} ", params)
+    }
+}
+
+pub(crate) fn format_params(params: &BTreeMap<String, String>) -> String {
+    params
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Exit codes, with reasons in place of codes where a process has one.
+fn format_exits(step: &StepReport) -> String {
+    if !step.exit_reasons.is_empty() {
+        return step.exit_reasons.join(",");
+    }
+    step.exit_codes
+        .iter()
+        .map(|code| code.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn print_step(step: &StepReport) {
+    println!(
+        "summary: step={} runtime={} processes={} duration_ms={} cpu_ms={} max_rss_kb={} exit={}",
+        step.id,
+        step.runtime,
+        step.pids.len(),
+        step.duration_ms,
+        step.cpu_ms,
+        step.max_rss_kb,
+        format_exits(step)
+    );
+    for profile in &step.profiles {
+        let mut line = format!(
+            "summary: step={} proc={} profile={}",
+            step.id, profile.proc_index, profile.profile
+        );
+        for counter in &profile.counters {
+            match counter.value {
+                Some(value) => line.push_str(&format!(" {}={}", counter.event, value)),
+                None => line.push_str(&format!(" {}=n/a", counter.event)),
+            }
+        }
+        let top = profile
+            .calls
+            .iter()
+            .filter(|row| row.name != "total")
+            .take(5)
+            .map(|row| format!("{}:{}", row.name, row.calls))
+            .collect::<Vec<_>>();
+        if !top.is_empty() {
+            line.push_str(&format!(" top={}", top.join(",")));
+        }
+        if profile.counters.is_empty() && profile.calls.is_empty() {
+            line.push_str(&format!(" output={}", profile.output.display()));
+        }
+        println!("{}", line);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::orchestrator::config::{LimitValue, Step};
+use crate::orchestrator::limits::memory_bytes;
+use crate::orchestrator::process::SpawnSettings;
+use crate::orchestrator::templating::parse_duration_ms;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// The resource argument of `setrlimit`: unsigned in glibc and uClibc,
+/// `int` in musl.
+#[cfg(any(target_env = "gnu", target_env = "uclibc"))]
+pub(crate) type Resource = libc::__rlimit_resource_t;
+#[cfg(not(any(target_env = "gnu", target_env = "uclibc")))]
+pub(crate) type Resource = libc::c_int;
+
+/// Names accepted in `rlimits` and the resource each one sets.
+pub(crate) const RLIMITS: &[(&str, Resource)] = &[
+    ("as", libc::RLIMIT_AS),
+    ("core", libc::RLIMIT_CORE),
+    ("cpu", libc::RLIMIT_CPU),
+    ("data", libc::RLIMIT_DATA),
+    ("fsize", libc::RLIMIT_FSIZE),
+    ("memlock", libc::RLIMIT_MEMLOCK),
+    ("nofile", libc::RLIMIT_NOFILE),
+    ("nproc", libc::RLIMIT_NPROC),
+    ("stack", libc::RLIMIT_STACK),
+];
+
+/// One `setrlimit` call made in the child before exec.
+#[derive(Clone, Copy)]
+pub(crate) struct Rlimit {
+    pub(crate) resource: Resource,
+    pub(crate) soft: u64,
+    pub(crate) hard: u64,
+}
+
+/// The step's `rlimits` and `oom_score_adj`, applied to each process.
+pub(crate) struct ProcessLimits {
+    rlimits: Vec<Rlimit>,
+    oom_score_adj: Option<i32>,
+    cpu_limit_secs: Option<u64>,
+    memory_limited: bool,
+    fields: String,
+}
+
+/// Collects the step's process limits; `None` when it sets none.
+pub(crate) fn process_limits(step: &Step) -> Option<ProcessLimits> {
+    if step.rlimits.is_none() && step.oom_score_adj.is_none() {
+        return None;
+    }
+    let mut names = step
+        .rlimits
+        .iter()
+        .flatten()
+        .map(|(name, value)| (name.as_str(), value))
+        .collect::<Vec<_>>();
+    names.sort_by_key(|(name, _)| *name);
+
+    let mut limits = ProcessLimits {
+        rlimits: Vec::new(),
+        oom_score_adj: step.oom_score_adj,
+        cpu_limit_secs: None,
+        memory_limited: false,
+        fields: String::new(),
+    };
+    for (name, value) in names {
+        let (Some(resource), Some(soft)) = (rlimit_resource(name), rlimit_value(name, value))
+        else {
+            continue;
+        };
+        // A soft CPU limit below the hard one delivers SIGXCPU instead of
+        // SIGKILL, so the exit reason can name it.
+        let hard = if name == "cpu" && soft != libc::RLIM_INFINITY {
+            limits.cpu_limit_secs = Some(soft);
+            soft + 1
+        } else {
+            soft
+        };
+        if name == "as" || name == "data" {
+            limits.memory_limited = true;
+        }
+        limits.rlimits.push(Rlimit {
+            resource,
+            soft,
+            hard,
+        });
+        let shown = match soft {
+            libc::RLIM_INFINITY => "unlimited".to_string(),
+            soft => soft.to_string(),
+        };
+        limits
+            .fields
+            .push_str(&format!(" rlimit.{}={}", name, shown));
+    }
+    if let Some(adj) = step.oom_score_adj {
+        limits.fields.push_str(&format!(" oom_score_adj={}", adj));
+    }
+    Some(limits)
+}
+
+impl ProcessLimits {
+    /// Sets the limits in the child before exec and notes them in `settings`.
+    pub(crate) fn prepare(&self, command: &mut Command, settings: &mut SpawnSettings) {
+        install_rlimits(command, self.rlimits.clone());
+        if let Some(adj) = self.oom_score_adj {
+            let path = c"/proc/self/oom_score_adj";
+            let text = adj.to_string().into_bytes();
+            // SAFETY: the hook only calls open/write/close, which are
+            // async-signal-safe, on memory owned by the closure.
+            unsafe {
+                command.pre_exec(move || {
+                    let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                    if fd < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    let written = libc::write(fd, text.as_ptr().cast(), text.len());
+                    let error = io::Error::last_os_error();
+                    libc::close(fd);
+                    if written < 0 {
+                        return Err(error);
+                    }
+                    Ok(())
+                });
+            }
+        }
+        settings.fields.push_str(&self.fields);
+        settings.cpu_limit_secs = self.cpu_limit_secs;
+        settings.memory_limited |= self.memory_limited;
+    }
+}
+
+/// Calls `setrlimit` for each limit in the child before exec.
+pub(crate) fn install_rlimits(command: &mut Command, rlimits: Vec<Rlimit>) {
+    if rlimits.is_empty() {
+        return;
+    }
+    // SAFETY: setrlimit is async-signal-safe and only reads owned values.
+    unsafe {
+        command.pre_exec(move || {
+            for limit in &rlimits {
+                let value = libc::rlimit {
+                    rlim_cur: limit.soft,
+                    rlim_max: limit.hard,
+                };
+                if libc::setrlimit(limit.resource, &value) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+pub(crate) fn rlimit_resource(name: &str) -> Option<Resource> {
+    RLIMITS
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, resource)| *resource)
+}
+
+/// A limit value: `unlimited`, a size for memory limits, a duration in
+/// seconds (`90`, `2m`) for `cpu`, or a count.
+pub(crate) fn rlimit_value(name: &str, value: &LimitValue) -> Option<u64> {
+    if let LimitValue::Text(text) = value
+        && matches!(text.trim(), "unlimited" | "infinity")
+    {
+        return Some(libc::RLIM_INFINITY);
+    }
+    match name {
+        "as" | "core" | "data" | "fsize" | "memlock" | "stack" => memory_bytes(value),
+        "cpu" => match value {
+            LimitValue::Number(secs) if *secs >= 1.0 && secs.fract() == 0.0 => Some(*secs as u64),
+            LimitValue::Number(_) => None,
+            LimitValue::Text(text) => {
+                let text = text.trim();
+                let ms = match text.parse::<u64>() {
+                    Ok(secs) => secs.checked_mul(1000)?,
+                    Err(_) => parse_duration_ms(text)?,
+                };
+                (ms > 0).then(|| ms.div_ceil(1000))
+            }
+        },
+        _ => match value {
+            LimitValue::Number(count) if *count >= 0.0 && count.fract() == 0.0 => {
+                Some(*count as u64)
+            }
+            LimitValue::Number(_) => None,
+            LimitValue::Text(text) => text.trim().parse().ok(),
+        },
+    }
+}
@@ -0,0 +1,273 @@
+use crate::orchestrator::config::{ConfigError, CpuList, CpuSpec, Step, step_processes};
+use std::error::Error;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// `ioprio_set` target type for a single process.
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+/// CPU placement and scheduling settings applied to every process of a step.
+pub(crate) struct Scheduling {
+    cpus: Vec<usize>,
+    spread: bool,
+    processes: u32,
+    /// Memory binding from `numa_node`, as a node bitmask.
+    memory_nodes: Option<u64>,
+    nice: Option<i32>,
+    /// `(class << 13) | level`, the value `ioprio_set` expects.
+    ioprio: Option<libc::c_int>,
+    policy: Option<(libc::c_int, libc::c_int)>,
+    /// Settings other than `cpus`, as shown on start lines.
+    fields: String,
+}
+
+/// Collects the step's scheduling settings; `None` when it sets none.
+pub(crate) fn scheduling(step: &Step) -> Result<Option<Scheduling>, Box<dyn Error>> {
+    if step.cpus.is_none()
+        && step.numa_node.is_none()
+        && step.nice.is_none()
+        && step.ionice.is_none()
+        && step.sched_policy.is_none()
+    {
+        return Ok(None);
+    }
+
+    let (mut cpus, spread) = match &step.cpus {
+        Some(CpuSpec::Set(set)) => (cpu_list(set).unwrap_or_default(), false),
+        Some(CpuSpec::Detailed(options)) => (
+            cpu_list(&options.set).unwrap_or_default(),
+            options.spread.unwrap_or(false),
+        ),
+        None => (Vec::new(), false),
+    };
+    let mut fields = String::new();
+    let memory_nodes = match step.numa_node {
+        Some(node) => {
+            let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+            let node_cpus = std::fs::read_to_string(&path)
+                .ok()
+                .and_then(|text| cpu_list(&CpuList::Line(text.trim().to_string())))
+                .ok_or_else(|| ConfigError(format!("numa_node {} not found ({})", node, path)))?;
+            if cpus.is_empty() {
+                cpus = node_cpus;
+            } else {
+                cpus.retain(|cpu| node_cpus.contains(cpu));
+                if cpus.is_empty() {
+                    return Err(Box::new(ConfigError(format!(
+                        "cpus has no CPU on numa_node {}",
+                        node
+                    ))));
+                }
+            }
+            fields.push_str(&format!(" numa_node={}", node));
+            Some(1u64 << node)
+        }
+        None => None,
+    };
+    if let Some(nice) = step.nice {
+        fields.push_str(&format!(" nice={}", nice));
+    }
+    let ioprio = match &step.ionice {
+        Some(ionice) => {
+            let class = ionice_class(&ionice.class).unwrap_or(2);
+            let level = if class == 3 {
+                0
+            } else {
+                ionice.level.unwrap_or(4) as libc::c_int
+            };
+            fields.push_str(&format!(" ionice={}:{}", ionice.class, level));
+            Some((class << IOPRIO_CLASS_SHIFT) | level)
+        }
+        None => None,
+    };
+    let policy = match &step.sched_policy {
+        Some(name) => {
+            let policy = sched_policy(name).unwrap_or(libc::SCHED_OTHER);
+            let priority = step.sched_priority.unwrap_or(0) as libc::c_int;
+            fields.push_str(&format!(" sched={}", name.to_lowercase()));
+            if priority > 0 {
+                fields.push_str(&format!(":{}", priority));
+            }
+            Some((policy, priority))
+        }
+        None => None,
+    };
+
+    Ok(Some(Scheduling {
+        cpus,
+        spread,
+        processes: step_processes(step),
+        memory_nodes,
+        nice: step.nice,
+        ioprio,
+        policy,
+        fields,
+    }))
+}
+
+impl Scheduling {
+    /// Applies the settings to process `proc_index` before exec and returns
+    /// them as ` key=value` pairs for its start line.
+    pub(crate) fn prepare(&self, command: &mut Command, proc_index: u32) -> String {
+        let cpus = self.process_cpus(proc_index);
+        let mut fields = String::new();
+        let affinity = if cpus.is_empty() {
+            None
+        } else {
+            fields.push_str(&format!(" cpus={}", format_cpus(&cpus)));
+            // SAFETY: cpu_set_t is plain old data; all-zero is the empty set.
+            let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            for cpu in &cpus {
+                // SAFETY: `cpu_list` keeps indexes below CPU_SETSIZE.
+                unsafe { libc::CPU_SET(*cpu, &mut set) };
+            }
+            Some(set)
+        };
+        fields.push_str(&self.fields);
+
+        let (memory_nodes, nice, ioprio, policy) =
+            (self.memory_nodes, self.nice, self.ioprio, self.policy);
+        // SAFETY: the hook only makes raw syscalls on values it owns, which
+        // is async-signal-safe.
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(set) = &affinity {
+                    check(libc::sched_setaffinity(
+                        0,
+                        std::mem::size_of::<libc::cpu_set_t>(),
+                        set,
+                    ))?;
+                }
+                if let Some(mask) = memory_nodes {
+                    let mask = [mask];
+                    check(libc::syscall(
+                        libc::SYS_set_mempolicy,
+                        libc::MPOL_BIND,
+                        mask.as_ptr(),
+                        u64::BITS as libc::c_ulong + 1,
+                    ) as libc::c_int)?;
+                }
+                if let Some((policy, priority)) = policy {
+                    let param = libc::sched_param {
+                        sched_priority: priority,
+                    };
+                    check(libc::sched_setscheduler(0, policy, &param))?;
+                }
+                if let Some(nice) = nice {
+                    check(libc::setpriority(libc::PRIO_PROCESS, 0, nice))?;
+                }
+                if let Some(ioprio) = ioprio {
+                    check(
+                        libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio)
+                            as libc::c_int,
+                    )?;
+                }
+                Ok(())
+            });
+        }
+        fields
+    }
+
+    /// With `spread`, process N gets the Nth equal slice of the set, or a
+    /// single CPU round-robin when there are more processes than CPUs.
+    fn process_cpus(&self, proc_index: u32) -> Vec<usize> {
+        if !self.spread || self.cpus.is_empty() {
+            return self.cpus.clone();
+        }
+        let count = self.cpus.len();
+        let processes = self.processes.max(1) as usize;
+        let index = proc_index as usize;
+        if processes >= count {
+            return vec![self.cpus[index % count]];
+        }
+        self.cpus[index * count / processes..(index + 1) * count / processes].to_vec()
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// CPU indexes in the order given, without duplicates.
+pub(crate) fn cpu_list(list: &CpuList) -> Option<Vec<usize>> {
+    let indexes = match list {
+        CpuList::Index(cpu) => vec![*cpu as usize],
+        CpuList::List(cpus) => cpus.iter().map(|cpu| *cpu as usize).collect(),
+        CpuList::Line(text) => {
+            let mut indexes = Vec::new();
+            for part in text.split(',') {
+                let part = part.trim();
+                match part.split_once('-') {
+                    Some((first, last)) => {
+                        let first = first.trim().parse::<usize>().ok()?;
+                        let last = last.trim().parse::<usize>().ok()?;
+                        if first > last {
+                            return None;
+                        }
+                        indexes.extend(first..=last);
+                    }
+                    None => indexes.push(part.parse().ok()?),
+                }
+            }
+            indexes
+        }
+    };
+    let mut cpus = Vec::with_capacity(indexes.len());
+    for cpu in indexes {
+        if cpu >= libc::CPU_SETSIZE as usize {
+            return None;
+        }
+        if !cpus.contains(&cpu) {
+            cpus.push(cpu);
+        }
+    }
+    (!cpus.is_empty()).then_some(cpus)
+}
+
+/// Formats CPUs as a list with ranges, e.g. `0-3,6`.
+fn format_cpus(cpus: &[usize]) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut index = 0;
+    while index < cpus.len() {
+        let mut end = index;
+        while end + 1 < cpus.len() && cpus[end + 1] == cpus[end] + 1 {
+            end += 1;
+        }
+        if end == index {
+            parts.push(cpus[index].to_string());
+        } else {
+            parts.push(format!("{}-{}", cpus[index], cpus[end]));
+        }
+        index = end + 1;
+    }
+    parts.join(",")
+}
+
+/// Policy constant for `other`, `batch`, `idle`, `fifo` or `rr`; a `SCHED_`
+/// prefix and upper case are accepted.
+pub(crate) fn sched_policy(name: &str) -> Option<libc::c_int> {
+    let name = name.to_lowercase();
+    match name.strip_prefix("sched_").unwrap_or(&name) {
+        "other" | "normal" => Some(libc::SCHED_OTHER),
+        "batch" => Some(libc::SCHED_BATCH),
+        "idle" => Some(libc::SCHED_IDLE),
+        "fifo" => Some(libc::SCHED_FIFO),
+        "rr" => Some(libc::SCHED_RR),
+        _ => None,
+    }
+}
+
+/// `ioprio` class number for `realtime`, `best-effort` or `idle`.
+pub(crate) fn ionice_class(name: &str) -> Option<libc::c_int> {
+    match name.to_lowercase().as_str() {
+        "realtime" | "rt" => Some(1),
+        "best-effort" | "be" => Some(2),
+        "idle" => Some(3),
+        _ => None,
+    }
+}
@@ -1,15 +1,16 @@
-use crate::orchestrator::config::{
-    ConfigError, Step, step_duration_ms, step_env, step_processes, step_stdout,
-};
-use crate::orchestrator::process::{kill_process, spawn_process, wait_process};
+use crate::orchestrator::cache::CacheContext;
+use crate::orchestrator::config::{ConfigError, Step, step_env, step_processes, step_stdout};
+use crate::orchestrator::controls::StepControls;
+use crate::orchestrator::process::PendingProcess;
+use crate::orchestrator::templating::{ProcessVars, expand_process_tokens};
 use crate::orchestrator::wrapper::wrap_command;
-use crate::orchestrator::StepOutcome;
 use std::error::Error;
-use std::process::Command;
-use std::thread;
-use std::time::Duration;
 
-pub(crate) fn run(step: &Step, on_start: &dyn Fn(&[u32])) -> Result<StepOutcome, Box<dyn Error>> {
+pub(crate) fn launch(
+    step: &Step,
+    cache: &CacheContext,
+    controls: &StepControls,
+) -> Result<Vec<PendingProcess>, Box<dyn Error>> {
     let command = step
         .command
         .as_deref()
@@ -17,52 +18,35 @@ pub(crate) fn run(step: &Step, on_start: &dyn Fn(&[u32])) -> Result<StepOutcome,
     let shell = step.shell.as_deref().unwrap_or("bash");
     let processes = step_processes(step);
     let stdout_enabled = step_stdout(step);
-    let duration_ms = step_duration_ms(step);
     let envs = step_env(step);
 
     println!("shell: processes={} shell={}", processes, shell);
 
     let step_id = step.id.as_deref().unwrap_or("unknown");
     let log_label = format!("step={} runtime=shell", step_id);
-    let mut base = vec![shell.to_string(), "-lc".to_string(), command.to_string()];
-
-    let mut children = Vec::new();
-    let mut pids = Vec::new();
-    for _ in 0..processes {
-        let wrapped = wrap_command(step, &base);
+    let base = vec![shell.to_string(), "-lc".to_string(), command.to_string()];
+
+    let mut pending = Vec::new();
+    for proc_index in 0..processes {
+        let vars = ProcessVars {
+            step_id,
+            run_dir: &cache.run_dir,
+            proc_index,
+        };
+        let wrapped = wrap_command(step, &base, &vars)?;
         let mut cmd = wrapped.command;
+        let settings = controls.prepare(&mut cmd, &vars)?;
         for (key, value) in &envs {
-            cmd.env(key, value);
-        }
-        let child = spawn_process(cmd, &log_label, &wrapped.display, stdout_enabled)?;
-        pids.push(child.pid());
-        children.push(child);
-    }
-
-    on_start(&pids);
-
-    if let Some(duration) = duration_ms {
-        thread::sleep(Duration::from_millis(duration));
-        for child in &mut children {
-            let _ = kill_process(child);
+            cmd.env(key, expand_process_tokens(value, &vars));
         }
+        pending.push(PendingProcess {
+            command: cmd,
+            log_label: log_label.clone(),
+            settings,
+            display: wrapped.display,
+            stdout_enabled,
+        });
     }
 
-    let mut exit_codes = Vec::new();
-    for child in children {
-        let code = wait_process(child, &log_label, duration_ms.is_some())?;
-        exit_codes.push(code);
-    }
-
-    println!("pids={}", join_pids(&pids));
-    println!("shell: done");
-
-    Ok(StepOutcome { pids, exit_codes })
-}
-
-fn join_pids(pids: &[u32]) -> String {
-    pids.iter()
-        .map(|pid| pid.to_string())
-        .collect::<Vec<_>>()
-        .join(",")
+    Ok(pending)
 }
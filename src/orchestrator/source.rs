@@ -1,11 +1,17 @@
+use crate::orchestrator::archive::{archive_kind, unpack_archive};
 use crate::orchestrator::cache::CacheContext;
 use crate::orchestrator::config::{ConfigError, Step};
+use crate::orchestrator::git::{ensure_git_cached, resolve_git_source};
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub(crate) struct ResolvedSource {
     pub(crate) path: PathBuf,
+    /// Root directory for multi-file sources (git checkouts, directories, archives).
+    pub(crate) dir: Option<PathBuf>,
     cleanup: bool,
 }
 
@@ -17,34 +23,80 @@ impl Drop for ResolvedSource {
     }
 }
 
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum RefreshPolicy {
+    Never,
+    Always,
+    Conditional,
+}
+
+/// Response metadata stored next to a cached URL source.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct UrlMeta {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    fetched_at_ms: u128,
+}
+
+struct Downloaded {
+    bytes: Vec<u8>,
+    meta: UrlMeta,
+}
+
 pub(crate) fn resolve_source(
     step: &Step,
     default_path: &Path,
     extension: &str,
     cache: &CacheContext,
 ) -> Result<ResolvedSource, Box<dyn Error>> {
+    if let Some(code) = &step.source {
+        let path = cache.inline_source_path(code, extension);
+        if !path.exists() {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, code)?;
+        }
+        return Ok(ResolvedSource {
+            path,
+            dir: None,
+            cleanup: false,
+        });
+    }
+
+    if let Some(git) = &step.git {
+        let checkout = resolve_git_source(git, refresh_policy(step, cache), cache)?;
+        return dir_source(step, checkout, &git.repo);
+    }
+
     let location = match &step.location {
         Some(location) => location,
         None => {
+            // Absolute, since processes run in the step's own working directory.
             return Ok(ResolvedSource {
-                path: default_path.to_path_buf(),
+                path: std::path::absolute(default_path)?,
+                dir: None,
                 cleanup: false,
-            })
+            });
         }
     };
 
+    let archive = archive_kind(location);
     if is_http_url(location) {
+        let extension = archive.map(|kind| kind.extension()).unwrap_or(extension);
         let url_cache_path = cache.url_source_path(location, extension);
-        if !url_cache_path.exists() {
-            if let Some(parent) = url_cache_path.parent() {
-                std::fs::create_dir_all(parent)?;
-            }
-            let bytes = download_bytes(location)?;
-            std::fs::write(&url_cache_path, bytes)?;
+        let policy = refresh_policy(step, cache);
+        let updated = refresh_url_cache(location, &url_cache_path, policy, cache)?;
+
+        if let Some(kind) = archive {
+            let dest = cache.unpack_path_for_archive(&url_cache_path);
+            let root = unpack_archive(&url_cache_path, kind, &dest)?;
+            return dir_source(step, root, location);
         }
 
         let source_cache_path = cache.config_source_path(location, extension);
-        if !source_cache_path.exists() {
+        if updated || !source_cache_path.exists() {
             if let Some(parent) = source_cache_path.parent() {
                 std::fs::create_dir_all(parent)?;
             }
@@ -53,37 +105,326 @@ pub(crate) fn resolve_source(
 
         Ok(ResolvedSource {
             path: source_cache_path,
+            dir: None,
             cleanup: false,
         })
     } else {
-        let path = PathBuf::from(location);
+        let path = std::path::absolute(location)?;
         if !path.exists() {
             return Err(Box::new(ConfigError(format!(
                 "location path '{}' does not exist",
                 location
             ))));
         }
+        if path.is_dir() {
+            return dir_source(step, path, location);
+        }
+        if let Some(kind) = archive {
+            let dest = cache.unpack_path_for_archive(&path);
+            let root = unpack_archive(&path, kind, &dest)?;
+            return dir_source(step, root, location);
+        }
         Ok(ResolvedSource {
             path,
+            dir: None,
             cleanup: false,
         })
     }
 }
 
+/// Selects `entry` inside a multi-file source rooted at `root`.
+fn dir_source(step: &Step, root: PathBuf, origin: &str) -> Result<ResolvedSource, Box<dyn Error>> {
+    let path = match &step.entry {
+        Some(entry) => root.join(entry),
+        None => root.clone(),
+    };
+    if !path.exists() {
+        return Err(Box::new(ConfigError(format!(
+            "entry '{}' does not exist in '{}'",
+            step.entry.as_deref().unwrap_or(""),
+            origin
+        ))));
+    }
+    if path.is_dir() && !matches!(step.runtime.to_lowercase().as_str(), "golang" | "go") {
+        return Err(Box::new(ConfigError(format!(
+            "'{}' is a directory; set 'entry' to the file to run",
+            origin
+        ))));
+    }
+    Ok(ResolvedSource {
+        path,
+        dir: Some(root),
+        cleanup: false,
+    })
+}
+
+/// Fails fast in offline mode when a URL source has never been downloaded.
+pub(crate) fn ensure_source_cached(
+    step: &Step,
+    cache: &CacheContext,
+) -> Result<(), Box<dyn Error>> {
+    if !cache.offline {
+        return Ok(());
+    }
+    if let Some(git) = &step.git {
+        return ensure_git_cached(git, cache);
+    }
+    let location = match &step.location {
+        Some(location) if is_http_url(location) => location,
+        _ => return Ok(()),
+    };
+    let meta_path = cache.url_meta_path(location);
+    let cached = meta_path
+        .parent()
+        .map(|dir| dir.exists() && has_cached_source(dir))
+        .unwrap_or(false);
+    if !cached {
+        return Err(Box::new(ConfigError(format!(
+            "step '{}': location '{}' is not cached (offline mode)",
+            step.id.as_deref().unwrap_or("unknown"),
+            location
+        ))));
+    }
+    Ok(())
+}
+
 pub(crate) fn is_http_url(value: &str) -> bool {
     value.starts_with("http://") || value.starts_with("https://")
 }
 
-fn download_bytes(url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
-    let response = ureq::get(url).call()?;
+pub(crate) fn refresh_policy(step: &Step, cache: &CacheContext) -> RefreshPolicy {
+    let value = cache
+        .refresh
+        .as_deref()
+        .or(step.refresh.as_deref())
+        .unwrap_or("never");
+    match value {
+        "always" => RefreshPolicy::Always,
+        "conditional" => RefreshPolicy::Conditional,
+        _ => RefreshPolicy::Never,
+    }
+}
+
+/// Downloads or revalidates the cached copy of `url`. Returns true when the
+/// cached bytes changed.
+fn refresh_url_cache(
+    url: &str,
+    url_cache_path: &Path,
+    policy: RefreshPolicy,
+    cache: &CacheContext,
+) -> Result<bool, Box<dyn Error>> {
+    let meta_path = cache.url_meta_path(url);
+    let cached = url_cache_path.exists();
+    if cached && (policy == RefreshPolicy::Never || cache.offline) {
+        return Ok(false);
+    }
+    if !cached && cache.offline {
+        return Err(Box::new(ConfigError(format!(
+            "location '{}' is not cached (offline mode)",
+            url
+        ))));
+    }
+
+    let previous = if cached && policy == RefreshPolicy::Conditional {
+        read_meta(&meta_path)
+    } else {
+        None
+    };
+
+    let Downloaded { bytes, meta } = match download(url, previous.as_ref())? {
+        Some(result) => result,
+        None => {
+            println!("source: not modified url={}", url);
+            return Ok(false);
+        }
+    };
+
+    if let Some(parent) = url_cache_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(url_cache_path, bytes)?;
+    std::fs::write(&meta_path, serde_json::to_vec_pretty(&meta)?)?;
+    if cached {
+        println!("source: refreshed url={}", url);
+    }
+    Ok(true)
+}
+
+fn has_cached_source(dir: &Path) -> bool {
+    std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .any(|entry| entry.file_name().to_string_lossy().starts_with("source."))
+        })
+        .unwrap_or(false)
+}
+
+fn read_meta(path: &Path) -> Option<UrlMeta> {
+    let content = std::fs::read(path).ok()?;
+    serde_json::from_slice(&content).ok()
+}
+
+/// Fetches `url`, sending validators from `previous` when present. Returns
+/// `None` when the server answers 304 Not Modified.
+fn download(url: &str, previous: Option<&UrlMeta>) -> Result<Option<Downloaded>, Box<dyn Error>> {
+    let mut request = ureq::get(url);
+    if let Some(previous) = previous {
+        if let Some(etag) = &previous.etag {
+            request = request.set("If-None-Match", etag);
+        }
+        if let Some(last_modified) = &previous.last_modified {
+            request = request.set("If-Modified-Since", last_modified);
+        }
+    }
+    let response = request.call()?;
     let status = response.status();
+    if status == 304 {
+        return Ok(None);
+    }
     if !(200..300).contains(&status) {
         return Err(Box::new(ConfigError(format!(
             "failed to download '{}': status {}",
             url, status
         ))));
     }
+    let meta = UrlMeta {
+        url: url.to_string(),
+        etag: response.header("ETag").map(|value| value.to_string()),
+        last_modified: response
+            .header("Last-Modified")
+            .map(|value| value.to_string()),
+        fetched_at_ms: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis(),
+    };
     let mut bytes = Vec::new();
     response.into_reader().read_to_end(&mut bytes)?;
-    Ok(bytes)
+    Ok(Some(Downloaded { bytes, meta }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    /// Serves `hello` with ETag `"v1"`, answering 304 to a matching
+    /// `If-None-Match`; returns the URL and the request heads it received.
+    fn serve() -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/workload.js", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line.to_lowercase());
+                }
+                let response = if head.contains("if-none-match: \"v1\"") {
+                    "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n"
+                } else {
+                    "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello"
+                };
+                seen.lock().unwrap().push(head);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn cache(name: &str, refresh: Option<&str>, offline: bool) -> CacheContext {
+        let base_dir =
+            std::env::temp_dir().join(format!("wl-source-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&base_dir);
+        CacheContext {
+            config_hash: String::new(),
+            run_id: "test".to_string(),
+            run_dir: base_dir.join("runs").join("test"),
+            source_dir: base_dir.join("source"),
+            url_dir: base_dir.join("url"),
+            git_dir: base_dir.join("git"),
+            base_dir,
+            refresh: refresh.map(str::to_string),
+            offline,
+        }
+    }
+
+    #[test]
+    fn conditional_refresh_revalidates_with_the_etag() {
+        let (url, requests) = serve();
+        let cache = cache("conditional", None, false);
+        let path = cache.url_source_path(&url, "js");
+
+        assert!(refresh_url_cache(&url, &path, RefreshPolicy::Conditional, &cache).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello");
+        assert!(!refresh_url_cache(&url, &path, RefreshPolicy::Conditional, &cache).unwrap());
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\""));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello");
+    }
+
+    #[test]
+    fn always_refresh_downloads_again() {
+        let (url, requests) = serve();
+        let cache = cache("always", None, false);
+        let path = cache.url_source_path(&url, "js");
+
+        assert!(refresh_url_cache(&url, &path, RefreshPolicy::Always, &cache).unwrap());
+        assert!(refresh_url_cache(&url, &path, RefreshPolicy::Always, &cache).unwrap());
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests[1].contains("if-none-match"));
+    }
+
+    #[test]
+    fn never_refresh_keeps_the_cached_copy() {
+        let (url, requests) = serve();
+        let cache = cache("never", None, false);
+        let path = cache.url_source_path(&url, "js");
+
+        assert!(refresh_url_cache(&url, &path, RefreshPolicy::Never, &cache).unwrap());
+        assert!(!refresh_url_cache(&url, &path, RefreshPolicy::Never, &cache).unwrap());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn offline_mode_never_downloads() {
+        let (url, requests) = serve();
+        let cache = cache("offline", None, true);
+        let path = cache.url_source_path(&url, "js");
+
+        let err = refresh_url_cache(&url, &path, RefreshPolicy::Always, &cache).unwrap_err();
+        assert!(err.to_string().contains("is not cached (offline mode)"));
+
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "cached").unwrap();
+        assert!(!refresh_url_cache(&url, &path, RefreshPolicy::Always, &cache).unwrap());
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn refresh_flag_overrides_the_step() {
+        let step: Step = serde_yaml::from_str("refresh: conditional").unwrap();
+        let plain: Step = serde_yaml::from_str("id: plain").unwrap();
+
+        let policy = |step: &Step, flag| refresh_policy(step, &cache("policy", flag, false));
+        assert!(policy(&step, None) == RefreshPolicy::Conditional);
+        assert!(policy(&step, Some("always")) == RefreshPolicy::Always);
+        assert!(policy(&step, Some("never")) == RefreshPolicy::Never);
+        assert!(policy(&plain, None) == RefreshPolicy::Never);
+    }
 }
@@ -0,0 +1,117 @@
+use crate::orchestrator::report::{RunReport, StepReport};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Distribution of one metric over repeated trials.
+#[derive(Debug, Serialize)]
+pub(crate) struct Stats {
+    pub(crate) n: usize,
+    pub(crate) mean: f64,
+    /// Sample standard deviation (n - 1).
+    pub(crate) stddev: f64,
+    pub(crate) min: f64,
+    pub(crate) max: f64,
+    pub(crate) p50: f64,
+    pub(crate) p90: f64,
+    pub(crate) p99: f64,
+}
+
+/// Per-step metric distributions across the measured trials of one point.
+#[derive(Debug, Serialize)]
+pub(crate) struct StepStats {
+    pub(crate) id: String,
+    pub(crate) metrics: BTreeMap<String, Stats>,
+}
+
+/// Aggregates duration, CPU, RSS, cgroup counters and profiler results per
+/// step. Profiler counters are summed over a step's processes before
+/// aggregating (`perf.<event>`, `calls.total`).
+pub(crate) fn aggregate(runs: &[RunReport]) -> Vec<StepStats> {
+    let mut order = Vec::new();
+    let mut samples: BTreeMap<String, BTreeMap<String, Vec<f64>>> = BTreeMap::new();
+    for run in runs {
+        for step in &run.steps {
+            if !samples.contains_key(&step.id) {
+                order.push(step.id.clone());
+            }
+            let metrics = samples.entry(step.id.clone()).or_default();
+            for (name, value) in step_metrics(step) {
+                metrics.entry(name).or_default().push(value);
+            }
+        }
+    }
+
+    order
+        .into_iter()
+        .map(|id| {
+            let metrics = samples
+                .remove(&id)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(name, values)| (name, describe(values)))
+                .collect();
+            StepStats { id, metrics }
+        })
+        .collect()
+}
+
+fn step_metrics(step: &StepReport) -> BTreeMap<String, f64> {
+    let mut metrics = BTreeMap::new();
+    metrics.insert("duration_ms".to_string(), step.duration_ms as f64);
+    metrics.insert("cpu_ms".to_string(), step.cpu_ms as f64);
+    metrics.insert("max_rss_kb".to_string(), step.max_rss_kb as f64);
+    if let Some(cgroup) = &step.cgroup {
+        for (key, value) in &cgroup.cpu_stat {
+            metrics.insert(format!("cgroup.cpu.{}", key), *value as f64);
+        }
+        for (key, value) in &cgroup.memory_events {
+            metrics.insert(format!("cgroup.memory.{}", key), *value as f64);
+        }
+    }
+    for profile in &step.profiles {
+        for counter in &profile.counters {
+            if let Some(value) = counter.value {
+                *metrics
+                    .entry(format!("perf.{}", counter.event))
+                    .or_default() += value;
+            }
+        }
+        if let Some(total) = profile.calls.iter().find(|row| row.name == "total") {
+            *metrics.entry("calls.total".to_string()).or_default() += total.calls as f64;
+        }
+    }
+    metrics
+}
+
+fn describe(mut values: Vec<f64>) -> Stats {
+    values.sort_by(f64::total_cmp);
+    let n = values.len();
+    let mean = values.iter().sum::<f64>() / n as f64;
+    let variance = if n > 1 {
+        values
+            .iter()
+            .map(|value| (value - mean).powi(2))
+            .sum::<f64>()
+            / (n - 1) as f64
+    } else {
+        0.0
+    };
+    Stats {
+        n,
+        mean,
+        stddev: variance.sqrt(),
+        min: values[0],
+        max: values[n - 1],
+        p50: percentile(&values, 0.50),
+        p90: percentile(&values, 0.90),
+        p99: percentile(&values, 0.99),
+    }
+}
+
+/// Linear interpolation between the closest ranks of sorted `values`.
+fn percentile(values: &[f64], fraction: f64) -> f64 {
+    let rank = fraction * (values.len() - 1) as f64;
+    let low = rank.floor() as usize;
+    let high = rank.ceil() as usize;
+    values[low] + (values[high] - values[low]) * (rank - low as f64)
+}
@@ -0,0 +1,865 @@
+use crate::orchestrator::cache::CacheContext;
+use crate::orchestrator::config::{Dependency, Phase, Step, step_duration_ms, step_processes};
+use crate::orchestrator::controls::StepControls;
+use crate::orchestrator::phase::{PhasePlan, PhaseReport};
+use crate::orchestrator::pipes::Pipes;
+use crate::orchestrator::process::{
+    ChildTracker, PendingProcess, ProcessExit, interrupt_process, kill_process, spawn_process,
+    wait_process,
+};
+use crate::orchestrator::profile::collect_profiles;
+use crate::orchestrator::report::StepReport;
+use crate::orchestrator::templating::{StepFacts, TemplateContext, apply_placeholders};
+use crate::orchestrator::{launch_step, observe, unix_millis};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::time::{Duration, Instant};
+
+/// How often children are checked for exit when pidfd_open is missing.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Signals that stop the run instead of killing `wl`.
+const STOP_SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+/// SIGINT and SIGTERM, blocked for the run and read from a signalfd, so that
+/// Ctrl-C stops the steps and still writes the run summary. Create it before
+/// any thread so every thread inherits the mask; dropping it restores the
+/// previous mask.
+pub(crate) struct Signals {
+    fd: OwnedFd,
+    previous: libc::sigset_t,
+}
+
+pub(crate) fn block_signals() -> io::Result<Signals> {
+    // SAFETY: sigset_t is plain old data; the calls only touch the locals.
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for signal in STOP_SIGNALS {
+            libc::sigaddset(&mut set, signal);
+        }
+        let mut previous: libc::sigset_t = std::mem::zeroed();
+        let result = libc::pthread_sigmask(libc::SIG_BLOCK, &set, &mut previous);
+        if result != 0 {
+            return Err(io::Error::from_raw_os_error(result));
+        }
+        let fd = libc::signalfd(-1, &set, libc::SFD_CLOEXEC | libc::SFD_NONBLOCK);
+        if fd < 0 {
+            let err = io::Error::last_os_error();
+            libc::pthread_sigmask(libc::SIG_SETMASK, &previous, std::ptr::null_mut());
+            return Err(err);
+        }
+        Ok(Signals {
+            fd: OwnedFd::from_raw_fd(fd),
+            previous,
+        })
+    }
+}
+
+impl Signals {
+    /// The next pending stop signal, if any.
+    fn read(&self) -> Option<libc::c_int> {
+        // SAFETY: signalfd_siginfo is plain old data; read fills at most its size.
+        unsafe {
+            let mut info: libc::signalfd_siginfo = std::mem::zeroed();
+            let size = std::mem::size_of::<libc::signalfd_siginfo>();
+            let count = libc::read(self.fd.as_raw_fd(), (&raw mut info).cast(), size);
+            (count == size as isize).then_some(info.ssi_signo as libc::c_int)
+        }
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        // SAFETY: `previous` is the mask saved by `block_signals`.
+        unsafe {
+            libc::pthread_sigmask(libc::SIG_SETMASK, &self.previous, std::ptr::null_mut());
+        }
+    }
+}
+
+/// What the supervisor hands back once every step has ended.
+pub(crate) struct RunOutcome {
+    pub(crate) phases: Vec<PhaseReport>,
+    pub(crate) steps: Vec<StepReport>,
+    /// The signal that stopped the run early.
+    pub(crate) interrupted: Option<&'static str>,
+    /// The first step failure, if any.
+    pub(crate) error: Option<String>,
+}
+
+/// Runs every step of a config from one event loop. Steps start once their
+/// phase and dependencies allow; the loop then waits in poll(2) on the
+/// children's pidfds and stdout pipes, the stop signals and the nearest step
+/// or phase deadline. Templating and builds happen on a launcher thread per
+/// starting step, so a slow `go build` does not hold up the loop; the loop
+/// spawns the prepared processes itself.
+pub(crate) struct Supervisor<'a> {
+    runs: Vec<StepRun>,
+    phases: PhasePlan,
+    cache: &'a CacheContext,
+    vars: Arc<HashMap<String, String>>,
+    pipes: Arc<Pipes>,
+    signals: &'a Signals,
+    /// eventfd launcher threads write to after sending their result.
+    wake: Arc<OwnedFd>,
+    launched: (Sender<Launched>, Receiver<Launched>),
+    /// Stop every step once one fails (`fail_fast`).
+    fail_fast: bool,
+    error: Option<String>,
+    interrupted: Option<&'static str>,
+}
+
+struct StepRun {
+    /// The step as configured; placeholders are applied when it starts.
+    step: Step,
+    phase: Option<usize>,
+    state: State,
+    /// Log prefix of whoever asked the step to stop: `phase` or `run`.
+    stop_requested: Option<&'static str>,
+    facts: StepFacts,
+    report: Option<StepReport>,
+}
+
+enum State {
+    /// Waiting for its phase or dependencies.
+    Waiting,
+    /// A launcher thread is preparing its processes.
+    Launching,
+    Running(Box<Running>),
+    Done,
+}
+
+/// A step whose processes have been prepared, then started.
+struct Running {
+    /// Processes the launcher prepared, spawned by the supervisor thread.
+    pending: Vec<PendingProcess>,
+    children: Vec<ChildTracker>,
+    /// Filled in as each child is reaped.
+    exits: Vec<Option<ProcessExit>>,
+    controls: StepControls,
+    log_label: String,
+    /// Line logged once every process has been reaped.
+    done: String,
+    observe_target: Option<String>,
+    runtime: String,
+    profile: Option<String>,
+    processes: u32,
+    started: Instant,
+    started_at_ms: u128,
+    duration: Option<Duration>,
+    /// Set once the processes have been spawned.
+    deadline: Option<Instant>,
+    /// Set once `wl` has killed or interrupted the processes.
+    signaled: bool,
+}
+
+impl Running {
+    /// Whether every process has been reaped and its stdout drained.
+    fn ended(&self) -> bool {
+        self.exits.iter().all(Option::is_some)
+            && self
+                .children
+                .iter()
+                .all(|child| child.stdout_fd().is_none())
+    }
+}
+
+/// A launcher thread's result for step `index`.
+struct Launched {
+    index: usize,
+    result: Result<Box<Running>, String>,
+}
+
+/// Whether a step can start now, later, or never.
+enum Readiness {
+    Ready,
+    Wait,
+    Never(String),
+}
+
+/// What a descriptor handed to poll(2) belongs to.
+enum Source {
+    Exit(usize, usize),
+    Stdout(usize, usize),
+}
+
+impl<'a> Supervisor<'a> {
+    pub(crate) fn new(
+        steps: Vec<Step>,
+        phases: &[Phase],
+        cache: &'a CacheContext,
+        vars: Arc<HashMap<String, String>>,
+        pipes: Arc<Pipes>,
+        signals: &'a Signals,
+        fail_fast: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        // SAFETY: eventfd takes integers and returns a new descriptor or -1.
+        let wake = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if wake < 0 {
+            return Err(Box::new(io::Error::last_os_error()));
+        }
+        let plan = PhasePlan::new(phases, &steps);
+        let runs = steps
+            .into_iter()
+            .map(|step| StepRun {
+                phase: step
+                    .phase
+                    .as_ref()
+                    .and_then(|name| phases.iter().position(|phase| &phase.name == name)),
+                step,
+                state: State::Waiting,
+                stop_requested: None,
+                facts: StepFacts::default(),
+                report: None,
+            })
+            .collect();
+        Ok(Supervisor {
+            runs,
+            phases: plan,
+            cache,
+            vars,
+            pipes,
+            signals,
+            // SAFETY: eventfd just opened the descriptor and nothing else owns it.
+            wake: Arc::new(unsafe { OwnedFd::from_raw_fd(wake) }),
+            launched: channel(),
+            fail_fast,
+            error: None,
+            interrupted: None,
+        })
+    }
+
+    /// Runs until every step has ended. The first step failure is returned in
+    /// the outcome, next to the reports of every step.
+    pub(crate) fn run(mut self) -> Result<RunOutcome, Box<dyn Error>> {
+        loop {
+            self.update();
+            if self.runs.iter().all(|run| matches!(run.state, State::Done)) {
+                break;
+            }
+            self.wait()?;
+        }
+        Ok(RunOutcome {
+            phases: self.phases.finish(),
+            steps: self.runs.into_iter().filter_map(|run| run.report).collect(),
+            interrupted: self.interrupted,
+            error: self.error,
+        })
+    }
+
+    /// Applies everything that follows from the current state: phases end
+    /// and start, steps start, get stopped or finish, until nothing changes.
+    fn update(&mut self) {
+        loop {
+            let runs = &self.runs;
+            let started = |id: &str| {
+                runs.iter().any(|run| {
+                    run.step.id.as_deref() == Some(id) && !matches!(run.state, State::Waiting)
+                })
+            };
+            let finished = |id: &str| {
+                runs.iter()
+                    .any(|run| run.step.id.as_deref() == Some(id) && run.facts.finished)
+            };
+            for id in self.phases.advance(started, finished) {
+                self.request_stop(&id);
+            }
+            let mut changed = false;
+            for index in 0..self.runs.len() {
+                changed |= self.update_step(index);
+            }
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    fn update_step(&mut self, index: usize) -> bool {
+        match self.runs[index].state {
+            State::Waiting => self.update_waiting(index),
+            State::Running(_) => self.update_running(index),
+            State::Launching | State::Done => false,
+        }
+    }
+
+    fn update_waiting(&mut self, index: usize) -> bool {
+        let step = &self.runs[index].step;
+        let id = step.id.as_deref().unwrap_or("unknown");
+        if let Some(by) = self.runs[index].stop_requested {
+            match &step.observe {
+                Some(observe) => {
+                    println!("observe: stopped before target={} started", observe.target)
+                }
+                None => println!("{}: step={} stopped before it started", by, id),
+            }
+            self.finish_unstarted(index);
+            return true;
+        }
+        match self.readiness(index) {
+            Readiness::Wait => false,
+            Readiness::Never(reason) => {
+                println!("depends: step={} skipped: {}", id, reason);
+                self.finish_unstarted(index);
+                true
+            }
+            Readiness::Ready => {
+                self.launch(index);
+                true
+            }
+        }
+    }
+
+    fn update_running(&mut self, index: usize) -> bool {
+        let target_done = match &self.runs[index].state {
+            State::Running(running) => running
+                .observe_target
+                .as_ref()
+                .and_then(|target| self.find(target))
+                .is_some_and(|target| target.facts.finished),
+            _ => false,
+        };
+        let run = &mut self.runs[index];
+        let State::Running(running) = &mut run.state else {
+            return false;
+        };
+        if !running.signaled {
+            let expired = running
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline);
+            if run.stop_requested.is_some() || expired || target_done {
+                signal_children(running);
+            }
+        }
+        if running.signaled {
+            for (child, exit) in running.children.iter_mut().zip(&running.exits) {
+                if exit.is_some() {
+                    child.close_stdout();
+                }
+            }
+        }
+        if !running.ended() {
+            return false;
+        }
+        self.finish(index);
+        true
+    }
+
+    fn readiness(&self, index: usize) -> Readiness {
+        let run = &self.runs[index];
+        if let Some(phase) = run.phase
+            && self.phases.started().is_none_or(|started| started < phase)
+        {
+            return Readiness::Wait;
+        }
+        let target = run.step.observe.as_ref().map(|observe| Dependency {
+            id: observe.target.clone(),
+            when: Some("started".to_string()),
+            exit_codes: None,
+        });
+        for dep in run.step.depends_on.iter().flatten().chain(&target) {
+            let Some(other) = self.find(&dep.id) else {
+                return Readiness::Never(format!("'{}' does not match any step id", dep.id));
+            };
+            if dependency_satisfied(dep, &other.facts) {
+                continue;
+            }
+            if other.facts.finished {
+                return Readiness::Never(format!(
+                    "'{}' ended without meeting when={}",
+                    dep.id,
+                    dep.when.as_deref().unwrap_or("started")
+                ));
+            }
+            return Readiness::Wait;
+        }
+        Readiness::Ready
+    }
+
+    /// Prepares the step's processes on a launcher thread.
+    fn launch(&mut self, index: usize) {
+        let run = &mut self.runs[index];
+        run.state = State::Launching;
+        let step = run.step.clone();
+        let facts = self.facts();
+        let target_pids = step
+            .observe
+            .as_ref()
+            .and_then(|observe| self.find(&observe.target))
+            .map(|target| target.facts.pids.clone())
+            .unwrap_or_default();
+        let cache = self.cache.clone();
+        let vars = self.vars.clone();
+        let pipes = self.pipes.clone();
+        let sender = self.launched.0.clone();
+        let wake = self.wake.clone();
+        std::thread::spawn(move || {
+            let result = launch(step, &cache, &vars, &facts, pipes, &target_pids)
+                .map_err(|err| err.to_string());
+            let _ = sender.send(Launched { index, result });
+            let one = 1u64.to_ne_bytes();
+            // SAFETY: writes eight bytes from a live local to the eventfd.
+            unsafe { libc::write(wake.as_raw_fd(), one.as_ptr().cast(), one.len()) };
+        });
+    }
+
+    /// Waits in poll(2) for the next event and handles it.
+    fn wait(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut fds = vec![
+            pollfd(self.wake.as_raw_fd()),
+            pollfd(self.signals.fd.as_raw_fd()),
+        ];
+        let mut sources = Vec::new();
+        let mut fallback = false;
+        let mut deadline = self.phases.deadline();
+        for (index, run) in self.runs.iter().enumerate() {
+            let State::Running(running) = &run.state else {
+                continue;
+            };
+            if !running.signaled
+                && let Some(step_deadline) = running.deadline
+            {
+                deadline = Some(deadline.map_or(step_deadline, |other| other.min(step_deadline)));
+            }
+            for (child_index, child) in running.children.iter().enumerate() {
+                if running.exits[child_index].is_none() {
+                    match child.exit_fd() {
+                        Some(fd) => {
+                            fds.push(pollfd(fd));
+                            sources.push(Source::Exit(index, child_index));
+                        }
+                        None => fallback = true,
+                    }
+                }
+                if let Some(fd) = child.stdout_fd() {
+                    fds.push(pollfd(fd));
+                    sources.push(Source::Stdout(index, child_index));
+                }
+            }
+        }
+        let mut timeout =
+            deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if fallback {
+            timeout = Some(timeout.map_or(POLL_INTERVAL, |timeout| timeout.min(POLL_INTERVAL)));
+        }
+        let timeout_ms = timeout.map_or(-1, |timeout| {
+            timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as libc::c_int
+        });
+
+        // SAFETY: `fds` is a live array of `fds.len()` pollfd entries.
+        let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+        if result < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(());
+            }
+            return Err(Box::new(err));
+        }
+
+        if fds[1].revents != 0
+            && let Some(signal) = self.signals.read()
+        {
+            self.interrupt(signal);
+        }
+        for (fd, source) in fds[2..].iter().zip(&sources) {
+            if fd.revents == 0 {
+                continue;
+            }
+            match *source {
+                Source::Exit(index, child_index) => self.reap(index, child_index),
+                Source::Stdout(index, child_index) => {
+                    if let State::Running(running) = &mut self.runs[index].state {
+                        running.children[child_index].read_stdout();
+                    }
+                }
+            }
+        }
+        if fallback {
+            for index in 0..self.runs.len() {
+                let State::Running(running) = &self.runs[index].state else {
+                    continue;
+                };
+                let exited = (0..running.children.len())
+                    .filter(|&child_index| {
+                        running.exits[child_index].is_none()
+                            && running.children[child_index].exit_fd().is_none()
+                            && running.children[child_index].has_exited()
+                    })
+                    .collect::<Vec<_>>();
+                for child_index in exited {
+                    self.reap(index, child_index);
+                }
+            }
+        }
+        if fds[0].revents != 0 {
+            let mut count = [0u8; 8];
+            // SAFETY: reads eight bytes into a live local from the eventfd.
+            unsafe {
+                libc::read(
+                    self.wake.as_raw_fd(),
+                    count.as_mut_ptr().cast(),
+                    count.len(),
+                )
+            };
+            while let Ok(launched) = self.launched.1.try_recv() {
+                self.started(launched);
+            }
+        }
+        Ok(())
+    }
+
+    /// Spawns the processes a launcher thread prepared. They are spawned
+    /// here rather than on the launcher thread because an isolated step's
+    /// shim dies with the thread that spawned it.
+    fn started(&mut self, launched: Launched) {
+        let run = &mut self.runs[launched.index];
+        let result = launched.result.map(|mut running| {
+            let mut error = None;
+            for process in std::mem::take(&mut running.pending) {
+                match spawn_process(
+                    process.command,
+                    &process.log_label,
+                    process.settings,
+                    &process.display,
+                    process.stdout_enabled,
+                ) {
+                    Ok(child) => {
+                        running.children.push(child);
+                        running.exits.push(None);
+                    }
+                    Err(err) => {
+                        error = Some(err.to_string());
+                        break;
+                    }
+                }
+            }
+            running.deadline = running.duration.map(|duration| Instant::now() + duration);
+            (running, error)
+        });
+        if let Some(id) = &run.step.id {
+            // Readers and writers on the other end of its pipes see EOF or
+            // EPIPE even if the step never started.
+            self.pipes.release(id);
+        }
+        match result {
+            Ok((mut running, error)) => {
+                run.facts.pids = running.children.iter().map(ChildTracker::pid).collect();
+                run.facts.started_at_ms = Some(unix_millis());
+                if error.is_some() {
+                    signal_children(&mut running);
+                }
+                run.state = State::Running(running);
+                if let Some(message) = error {
+                    self.fail(launched.index, message);
+                }
+            }
+            Err(message) => {
+                run.facts.finished = true;
+                run.state = State::Done;
+                self.fail(launched.index, message);
+            }
+        }
+    }
+
+    fn reap(&mut self, index: usize, child_index: usize) {
+        let State::Running(running) = &mut self.runs[index].state else {
+            return;
+        };
+        if running.exits[child_index].is_some() {
+            return;
+        }
+        match wait_process(&mut running.children[child_index], &running.log_label) {
+            Ok(exit) => running.exits[child_index] = Some(exit),
+            Err(err) => {
+                // Without a status the child cannot be waited for again.
+                running.exits[child_index] = Some(ProcessExit {
+                    code: -1,
+                    reason: None,
+                    cpu_ms: 0,
+                    max_rss_kb: 0,
+                });
+                self.fail(index, err.to_string());
+            }
+        }
+    }
+
+    /// Reports a step whose processes have all been reaped.
+    fn finish(&mut self, index: usize) {
+        let run = &mut self.runs[index];
+        let State::Running(running) = std::mem::replace(&mut run.state, State::Done) else {
+            return;
+        };
+        let running = *running;
+        let report_id = run.step.id.clone().unwrap_or_else(|| "unknown".to_string());
+        let exits = running.exits.into_iter().flatten().collect::<Vec<_>>();
+        let pids = running
+            .children
+            .iter()
+            .map(ChildTracker::pid)
+            .collect::<Vec<_>>();
+        if running.observe_target.is_none() {
+            println!("pids={}", join_pids(&pids));
+        }
+        println!("{}", running.done);
+
+        let duration_ms = running.started.elapsed().as_millis();
+        let cgroup = running.controls.finish(&report_id);
+        let exit_codes = exits.iter().map(|exit| exit.code).collect::<Vec<_>>();
+        run.facts.finished = true;
+        run.facts.exit_codes = exit_codes.clone();
+        let exit_reasons = if exits.iter().any(|exit| exit.reason.is_some()) {
+            exits.iter().map(ProcessExit::label).collect()
+        } else {
+            Vec::new()
+        };
+        // Failures are expected once the step is bounded by a duration or was
+        // stopped, and from observers, which are always interrupted.
+        let allow_failure =
+            running.duration.is_some() || running.signaled || running.observe_target.is_some();
+        let failure = pids
+            .iter()
+            .zip(&exits)
+            .find(|(_, exit)| exit.code != 0)
+            .filter(|_| !allow_failure)
+            .map(|(pid, exit)| format!("process pid {} exited with {}", pid, exit.label()));
+
+        let profiles = running
+            .profile
+            .as_ref()
+            .map(|profile| {
+                collect_profiles(profile, &report_id, &self.cache.run_dir, running.processes)
+            })
+            .transpose();
+        let profiles = match profiles {
+            Ok(profiles) => profiles.unwrap_or_default(),
+            Err(err) => {
+                self.fail(index, err.to_string());
+                Vec::new()
+            }
+        };
+        let run = &mut self.runs[index];
+        run.report = Some(StepReport {
+            id: report_id,
+            runtime: running.runtime,
+            started_at_ms: running.started_at_ms,
+            duration_ms,
+            pids,
+            exit_codes,
+            exit_reasons,
+            cpu_ms: exits.iter().map(|exit| exit.cpu_ms).sum(),
+            max_rss_kb: exits.iter().map(|exit| exit.max_rss_kb).max().unwrap_or(0),
+            cgroup,
+            profiles,
+        });
+        if let Some(message) = failure {
+            self.fail(index, message);
+        }
+    }
+
+    /// Ends a step that never started, with an empty report.
+    fn finish_unstarted(&mut self, index: usize) {
+        let run = &mut self.runs[index];
+        run.state = State::Done;
+        run.facts.finished = true;
+        if let Some(id) = &run.step.id {
+            self.pipes.release(id);
+        }
+        run.report = Some(StepReport {
+            id: run.step.id.clone().unwrap_or_else(|| "unknown".to_string()),
+            runtime: step_runtime(&run.step),
+            started_at_ms: unix_millis(),
+            duration_ms: 0,
+            pids: Vec::new(),
+            exit_codes: Vec::new(),
+            exit_reasons: Vec::new(),
+            cpu_ms: 0,
+            max_rss_kb: 0,
+            cgroup: None,
+            profiles: Vec::new(),
+        });
+    }
+
+    /// Keeps the first error and, with `fail_fast`, stops every other step.
+    /// Errors after an interrupt are expected, e.g. from a build the signal
+    /// also reached.
+    fn fail(&mut self, index: usize, message: String) {
+        if self.error.is_some() || self.interrupted.is_some() {
+            return;
+        }
+        let id = self.runs[index].step.id.as_deref().unwrap_or("unknown");
+        if self.fail_fast {
+            println!("run: step={} failed, stopping the remaining steps", id);
+            self.stop_all();
+        } else {
+            println!("run: step={} failed", id);
+        }
+        self.error = Some(message);
+    }
+
+    fn interrupt(&mut self, signal: libc::c_int) {
+        let name = if signal == libc::SIGINT {
+            "SIGINT"
+        } else {
+            "SIGTERM"
+        };
+        println!("run: received {}, stopping every step", name);
+        self.interrupted.get_or_insert(name);
+        self.stop_all();
+    }
+
+    /// Stops every step and starts no further phases.
+    fn stop_all(&mut self) {
+        self.phases.abort();
+        for run in &mut self.runs {
+            run.stop_requested.get_or_insert("run");
+        }
+    }
+
+    /// Stops a step at the end of its phase or of the last phase.
+    fn request_stop(&mut self, id: &str) {
+        for run in &mut self.runs {
+            if run.step.id.as_deref() == Some(id) {
+                run.stop_requested.get_or_insert("phase");
+            }
+        }
+    }
+
+    fn find(&self, id: &str) -> Option<&StepRun> {
+        self.runs
+            .iter()
+            .find(|run| run.step.id.as_deref() == Some(id))
+    }
+
+    /// Facts about the steps that have started, ended or been asked to stop,
+    /// for placeholders such as `{id:pid}`.
+    fn facts(&self) -> HashMap<String, StepFacts> {
+        self.runs
+            .iter()
+            .filter(|run| {
+                run.facts.started_at_ms.is_some()
+                    || run.facts.finished
+                    || run.stop_requested.is_some()
+            })
+            .filter_map(|run| Some((run.step.id.clone()?, run.facts.clone())))
+            .collect()
+    }
+}
+
+/// Applies placeholders, sets up the step's controls and prepares its
+/// processes; runs on a launcher thread.
+fn launch(
+    step: Step,
+    cache: &CacheContext,
+    vars: &HashMap<String, String>,
+    facts: &HashMap<String, StepFacts>,
+    pipes: Arc<Pipes>,
+    target_pids: &[u32],
+) -> Result<Box<Running>, Box<dyn Error>> {
+    let started_at_ms = unix_millis();
+    let step = apply_placeholders(
+        &step,
+        &TemplateContext {
+            steps: facts,
+            run_id: &cache.run_id,
+            run_dir: &cache.run_dir,
+            step_id: step.id.as_deref().unwrap_or("unknown"),
+            started_at_ms,
+            vars,
+        },
+    )?;
+    let controls = StepControls::new(&step, cache, pipes)?;
+    let step_id = step.id.as_deref().unwrap_or("unknown");
+    let started = Instant::now();
+    let (pending, log_label, done) = match &step.observe {
+        Some(observe) => (
+            observe::launch(&step, observe, cache, target_pids)?,
+            observe::log_label(step_id, observe),
+            format!("observe: done target={}", observe.target),
+        ),
+        None => {
+            let (name, pending) = launch_step(&step, cache, &controls)?;
+            (
+                pending,
+                format!("step={} runtime={}", step_id, name),
+                format!("{}: done", name),
+            )
+        }
+    };
+    Ok(Box::new(Running {
+        pending,
+        children: Vec::new(),
+        exits: Vec::new(),
+        controls,
+        log_label,
+        done,
+        observe_target: step.observe.as_ref().map(|observe| observe.target.clone()),
+        runtime: step_runtime(&step),
+        profile: step.profile.clone(),
+        processes: step_processes(&step),
+        started,
+        started_at_ms,
+        duration: step_duration_ms(&step).map(Duration::from_millis),
+        deadline: None,
+        signaled: false,
+    }))
+}
+
+/// Kills a workload's processes, or interrupts (SIGINT) an observer's so the
+/// tools detach and flush their output. Reaped children are skipped, as their
+/// pids may have been reused.
+fn signal_children(running: &mut Running) {
+    running.signaled = true;
+    for (child, exit) in running.children.iter_mut().zip(&running.exits) {
+        if exit.is_some() {
+            continue;
+        }
+        let _ = match running.observe_target {
+            Some(_) => interrupt_process(child),
+            None => kill_process(child),
+        };
+    }
+}
+
+fn dependency_satisfied(dep: &Dependency, facts: &StepFacts) -> bool {
+    match dep.when.as_deref().unwrap_or("started") {
+        "started" => facts.started_at_ms.is_some(),
+        "exited" => {
+            if !facts.finished {
+                return false;
+            }
+            // A step skipped or failing to start has no exit codes to match.
+            if let Some(codes) = &dep.exit_codes {
+                return !facts.exit_codes.is_empty()
+                    && facts.exit_codes.iter().all(|code| codes.contains(code));
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+fn step_runtime(step: &Step) -> String {
+    match &step.observe {
+        Some(_) => "observe".to_string(),
+        None => step.runtime.clone(),
+    }
+}
+
+fn pollfd(fd: RawFd) -> libc::pollfd {
+    libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    }
+}
+
+fn join_pids(pids: &[u32]) -> String {
+    pids.iter()
+        .map(|pid| pid.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
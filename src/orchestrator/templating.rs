@@ -1,94 +1,1017 @@
-use crate::orchestrator::config::{ConfigError, Step};
+use crate::orchestrator::config::{ConfigError, Count, Step, WrapperArgs, WrapperSpec};
 use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+/// Tokens resolved per process when the command line is built, not per step.
+const PROCESS_TOKENS: &[&str] = &["proc_index"];
+
+/// Brackets a token deferred to process spawn. Private-use code points keep
+/// it apart from literal braces produced by `{{`/`}}` escapes.
+const DEFER_OPEN: char = '\u{E000}';
+const DEFER_CLOSE: char = '\u{E001}';
+
+/// Braces an expanded `p"..."` wrapper value contains (from `{{`/`}}` or an
+/// expanded value), kept literal when wrapper tokens are expanded.
+const LITERAL_OPEN: char = '\u{E002}';
+const LITERAL_CLOSE: char = '\u{E003}';
+
+pub(crate) struct ProcessVars<'a> {
+    pub(crate) step_id: &'a str,
+    pub(crate) run_dir: &'a Path,
+    pub(crate) proc_index: u32,
+}
+
+/// What is known about another step when placeholders are expanded.
+#[derive(Clone, Default)]
+pub(crate) struct StepFacts {
+    pub(crate) pids: Vec<u32>,
+    pub(crate) exit_codes: Vec<i32>,
+    pub(crate) finished: bool,
+    pub(crate) started_at_ms: Option<u128>,
+}
+
+pub(crate) struct TemplateContext<'a> {
+    pub(crate) steps: &'a HashMap<String, StepFacts>,
+    pub(crate) run_id: &'a str,
+    pub(crate) run_dir: &'a Path,
+    pub(crate) step_id: &'a str,
+    pub(crate) started_at_ms: u128,
+    pub(crate) vars: &'a HashMap<String, String>,
+}
 
 pub(crate) fn apply_placeholders(
     step: &Step,
-    pid_map: &HashMap<String, Vec<u32>>,
-) -> Result<Step, Box<dyn std::error::Error>> {
+    ctx: &TemplateContext,
+) -> Result<Step, Box<dyn Error>> {
     let mut updated = step.clone();
+    let expander = Expander { ctx };
 
     if let Some(command) = &step.command {
-        updated.command = Some(expand_value(command, pid_map)?);
+        updated.command = Some(expander.value(command, "command")?);
     }
     if let Some(wrapper) = &step.wrapper {
-        updated.wrapper = Some(expand_value(wrapper, pid_map)?);
+        updated.wrapper = Some(expander.wrapper(wrapper, "wrapper")?);
+    }
+    if let Some(wrappers) = &step.wrappers {
+        updated.wrappers = Some(
+            wrappers
+                .iter()
+                .enumerate()
+                .map(|(index, wrapper)| expander.wrapper(wrapper, &format!("wrappers[{}]", index)))
+                .collect::<Result<_, _>>()?,
+        );
     }
     if let Some(env) = &step.env {
         let mut new_env = env.clone();
         for (key, value) in env {
-            new_env.insert(key.clone(), expand_value(value, pid_map)?);
+            new_env.insert(key.clone(), expander.value(value, &format!("env.{}", key))?);
         }
         updated.env = Some(new_env);
     }
+    if let Some(args) = &step.args {
+        updated.args = Some(expander.list(args, "args")?);
+    }
+    if let Some(exec) = &step.exec {
+        updated.exec = Some(expander.value(exec, "exec")?);
+    }
+    if let Some(location) = &step.location {
+        updated.location = Some(expander.value(location, "location")?);
+    }
+    if let Some(cwd) = &step.cwd {
+        updated.cwd = Some(expander.value(cwd, "cwd")?);
+    }
+    if let Some(stdin) = updated.stdin.as_mut() {
+        if let Some(file) = stdin.file.take() {
+            stdin.file = Some(expander.value(&file, "stdin.file")?);
+        }
+        if let Some(text) = stdin.text.take() {
+            stdin.text = Some(expander.value(&text, "stdin.text")?);
+        }
+    }
+    if let Some(duration) = &step.duration_ms {
+        updated.duration_ms = Some(expander.duration(duration, "duration_ms")?);
+    }
+    if let Some(processes) = step.parallel.as_ref().and_then(|p| p.processes.as_ref()) {
+        let processes = expander.count(processes, "parallel.processes")?;
+        if let Some(parallel) = updated.parallel.as_mut() {
+            parallel.processes = Some(processes);
+        }
+    }
 
     Ok(updated)
 }
 
-fn expand_value(
-    value: &str,
-    pid_map: &HashMap<String, Vec<u32>>,
-) -> Result<String, Box<dyn std::error::Error>> {
-    if !value.starts_with("p\"") {
-        return Ok(value.to_string());
+/// Resolves tokens deferred by `apply_placeholders` for one process.
+pub(crate) fn expand_process_tokens(value: &str, vars: &ProcessVars) -> String {
+    value.replace(
+        &format!("{}proc_index{}", DEFER_OPEN, DEFER_CLOSE),
+        &vars.proc_index.to_string(),
+    )
+}
+
+/// Like `expand_process_tokens`, but wrappers also accept plain
+/// `{proc_index}`, `{run_dir}` and `{step_id}` without `p"..."`. Tokens are
+/// expanded in one pass, so substituted text is never expanded again.
+pub(crate) fn expand_wrapper_tokens(value: &str, vars: &ProcessVars) -> String {
+    let chars = value.chars().collect::<Vec<_>>();
+    let mut output = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let close = match chars[i] {
+            '{' => Some('}'),
+            DEFER_OPEN => Some(DEFER_CLOSE),
+            _ => None,
+        };
+        let token = close.and_then(|close| {
+            let len = chars[i + 1..].iter().position(|c| *c == close)?;
+            let name = chars[i + 1..i + 1 + len].iter().collect::<String>();
+            wrapper_token(&name, vars).map(|value| (value, len + 2))
+        });
+        match token {
+            Some((value, len)) => {
+                output.push_str(&value);
+                i += len;
+            }
+            None => {
+                output.push(match chars[i] {
+                    LITERAL_OPEN => '{',
+                    LITERAL_CLOSE => '}',
+                    c => c,
+                });
+                i += 1;
+            }
+        }
     }
+    output
+}
 
-    let trimmed = value.strip_prefix("p\"").unwrap_or("");
-    let rest = trimmed.strip_suffix('"').ok_or_else(|| {
-        ConfigError("placeholder missing closing '\"'".to_string())
-    })?;
+fn wrapper_token(name: &str, vars: &ProcessVars) -> Option<String> {
+    match name {
+        "proc_index" => Some(vars.proc_index.to_string()),
+        "run_dir" => Some(vars.run_dir.to_string_lossy().to_string()),
+        "step_id" => Some(vars.step_id.to_string()),
+        _ => None,
+    }
+}
 
-    let mut output = String::new();
-    let mut rest = rest;
-    while let Some(start) = rest.find('{') {
-        output.push_str(&rest[..start]);
-        let after = &rest[start + 1..];
-        let end = after.find('}').ok_or_else(|| {
-            ConfigError("placeholder missing closing '}'".to_string())
+/// Rewrites the step ids of `{id:key}` references in a `p"..."` value, e.g.
+/// when steps from an included file are namespaced. Other text is untouched.
+pub(crate) fn rename_step_refs(value: &str, rename: &dyn Fn(&str) -> Option<String>) -> String {
+    let body = match value.strip_prefix("p\"") {
+        Some(body) => body,
+        None => return value.to_string(),
+    };
+    let chars = body.chars().collect::<Vec<_>>();
+    let mut output = String::from("p\"");
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if (c == '{' || c == '}') && chars.get(i + 1) == Some(&c) {
+            output.push(c);
+            output.push(c);
+            i += 2;
+        } else if c == '{' {
+            output.push(c);
+            i += 1;
+            // Expression part: rename `id:key` names, keep everything else.
+            while i < chars.len() && chars[i] != '}' && chars[i] != '|' {
+                if chars[i].is_alphabetic() || chars[i] == '_' {
+                    let start = i;
+                    while i < chars.len()
+                        && (chars[i].is_alphanumeric() || "_.-:,".contains(chars[i]))
+                    {
+                        i += 1;
+                    }
+                    let name = chars[start..i].iter().collect::<String>();
+                    output.push_str(&rename_ref(&name, rename));
+                } else {
+                    output.push(chars[i]);
+                    i += 1;
+                }
+            }
+            // Default part, copied verbatim (quoted defaults may contain `}`).
+            if chars.get(i) == Some(&'|') {
+                output.push('|');
+                i += 1;
+                if let Some(&quote) = chars.get(i).filter(|c| **c == '"' || **c == '\'') {
+                    output.push(quote);
+                    i += 1;
+                    while i < chars.len() && chars[i] != quote {
+                        if chars[i] == '\\' && chars.get(i + 1) == Some(&quote) {
+                            output.push(chars[i]);
+                            i += 1;
+                        }
+                        output.push(chars[i]);
+                        i += 1;
+                    }
+                }
+                while i < chars.len() && chars[i] != '}' {
+                    output.push(chars[i]);
+                    i += 1;
+                }
+            }
+        } else {
+            output.push(c);
+            i += 1;
+        }
+    }
+    output
+}
+
+fn rename_ref(name: &str, rename: &dyn Fn(&str) -> Option<String>) -> String {
+    if name.starts_with("env:") || name.starts_with("var:") {
+        return name.to_string();
+    }
+    match name.rsplit_once(':') {
+        Some((id, key)) => match rename(id) {
+            Some(id) => format!("{}:{}", id, key),
+            None => name.to_string(),
+        },
+        None => name.to_string(),
+    }
+}
+
+/// A parsed `p"..."` body: literal text and `{expr|default}` placeholders.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Segment {
+    Literal(String),
+    Placeholder {
+        expr: String,
+        default: Option<String>,
+        column: usize,
+    },
+}
+
+/// A template syntax error at a 1-based column of the original value.
+#[derive(Debug, PartialEq)]
+pub(crate) struct TemplateError {
+    pub(crate) column: usize,
+    pub(crate) message: String,
+}
+
+/// Parses a template body. `{{` and `}}` are literal braces; a default may be
+/// quoted (`{env:X|"a } b"}`) to contain `}` or `|`. `first_column` is the
+/// column of the body's first character in the original value.
+pub(crate) fn parse_template(
+    body: &str,
+    first_column: usize,
+) -> Result<Vec<Segment>, TemplateError> {
+    let chars = body.chars().collect::<Vec<_>>();
+    let error = |index: usize, message: String| TemplateError {
+        column: first_column + index,
+        message,
+    };
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '{' if chars.get(i + 1) == Some(&'{') => {
+                literal.push('{');
+                i += 2;
+            }
+            '}' if chars.get(i + 1) == Some(&'}') => {
+                literal.push('}');
+                i += 2;
+            }
+            '}' => {
+                return Err(error(
+                    i,
+                    "unmatched '}' (use '}}' for a literal brace)".to_string(),
+                ));
+            }
+            '{' => {
+                let open = i;
+                i += 1;
+                let mut expr = String::new();
+                let mut default = None;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(error(open, "unclosed '{'".to_string())),
+                        Some('}') => break,
+                        Some('{') => {
+                            return Err(error(i, "unexpected '{' inside placeholder".to_string()));
+                        }
+                        Some('|') => {
+                            let (value, next) = parse_default(&chars, i + 1)
+                                .map_err(|(index, message)| error(index, message))?;
+                            default = Some(value);
+                            i = next;
+                            if chars.get(i) != Some(&'}') {
+                                return Err(error(open, "unclosed '{'".to_string()));
+                            }
+                            break;
+                        }
+                        Some(c) => {
+                            expr.push(*c);
+                            i += 1;
+                        }
+                    }
+                }
+                if expr.trim().is_empty() {
+                    return Err(error(open, "empty placeholder".to_string()));
+                }
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Placeholder {
+                    expr: expr.trim().to_string(),
+                    default,
+                    column: first_column + open,
+                });
+                i += 1;
+            }
+            c => {
+                literal.push(c);
+                i += 1;
+            }
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    Ok(segments)
+}
+
+/// Reads a default starting at `start`; returns it and the index of the
+/// character after it. Quoted defaults honour `\` escapes of the quote.
+fn parse_default(chars: &[char], start: usize) -> Result<(String, usize), (usize, String)> {
+    let mut value = String::new();
+    let mut i = start;
+    match chars.get(i) {
+        Some(quote @ ('"' | '\'')) => {
+            let quote = *quote;
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err((start, format!("unterminated {} in default", quote))),
+                    Some('\\') if chars.get(i + 1) == Some(&quote) => {
+                        value.push(quote);
+                        i += 2;
+                    }
+                    Some(c) if *c == quote => return Ok((value, i + 1)),
+                    Some(c) => {
+                        value.push(*c);
+                        i += 1;
+                    }
+                }
+            }
+        }
+        _ => {
+            while let Some(c) = chars.get(i) {
+                if *c == '}' {
+                    break;
+                }
+                value.push(*c);
+                i += 1;
+            }
+            Ok((value, i))
+        }
+    }
+}
+
+/// Expands fields of one step, citing step id, field and column on error.
+struct Expander<'a> {
+    ctx: &'a TemplateContext<'a>,
+}
+
+impl Expander<'_> {
+    fn error(&self, field: &str, column: usize, message: &str) -> Box<dyn Error> {
+        Box::new(ConfigError(format!(
+            "step '{}' field '{}' column {}: {}",
+            self.ctx.step_id, field, column, message
+        )))
+    }
+
+    fn value(&self, value: &str, field: &str) -> Result<String, Box<dyn Error>> {
+        let body = match value.strip_prefix("p\"") {
+            Some(body) => body,
+            None => return Ok(value.to_string()),
+        };
+        let body = body.strip_suffix('"').ok_or_else(|| {
+            self.error(
+                field,
+                value.chars().count(),
+                "placeholder missing closing '\"'",
+            )
+        })?;
+
+        let segments =
+            parse_template(body, 3).map_err(|err| self.error(field, err.column, &err.message))?;
+        let mut output = String::new();
+        for segment in segments {
+            match segment {
+                Segment::Literal(text) => output.push_str(&text),
+                Segment::Placeholder {
+                    expr,
+                    default,
+                    column,
+                } => {
+                    let expanded = expand_token(&expr, default.as_deref(), self.ctx)
+                        .map_err(|err| self.error(field, column, &err.to_string()))?;
+                    output.push_str(&expanded);
+                }
+            }
+        }
+        Ok(output)
+    }
+
+    fn list(&self, values: &[String], field: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| self.value(value, &format!("{}[{}]", field, index)))
+            .collect()
+    }
+
+    fn count(&self, count: &Count, field: &str) -> Result<Count, Box<dyn Error>> {
+        self.number(count, field, "a number", |text| text.parse().ok())
+    }
+
+    fn duration(&self, count: &Count, field: &str) -> Result<Count, Box<dyn Error>> {
+        self.number(
+            count,
+            field,
+            "a duration (500, 250ms, 60s, 5m)",
+            parse_duration_ms,
+        )
+    }
+
+    fn number(
+        &self,
+        count: &Count,
+        field: &str,
+        expected: &str,
+        parse: fn(&str) -> Option<u64>,
+    ) -> Result<Count, Box<dyn Error>> {
+        let template = match count {
+            Count::Value(value) => return Ok(Count::Value(*value)),
+            Count::Template(template) => template,
+        };
+        let expanded = self.value(template, field)?;
+        let value = parse(expanded.trim()).ok_or_else(|| {
+            ConfigError(format!(
+                "step '{}' field '{}': must be {} (got '{}')",
+                self.ctx.step_id, field, expected, expanded
+            ))
         })?;
-        let token = &after[..end];
-        output.push_str(&expand_token(token, pid_map)?);
-        rest = &after[end + 1..];
+        if value == 0 {
+            return Err(Box::new(ConfigError(format!(
+                "step '{}' field '{}': must be > 0",
+                self.ctx.step_id, field
+            ))));
+        }
+        Ok(Count::Value(value))
+    }
+
+    fn wrapper(&self, wrapper: &WrapperSpec, field: &str) -> Result<WrapperSpec, Box<dyn Error>> {
+        let expanded = match wrapper {
+            WrapperSpec::Line(line) => WrapperSpec::Line(self.wrapper_value(line, field)?),
+            WrapperSpec::Args(args) => WrapperSpec::Args(self.wrapper_list(args, field)?),
+            WrapperSpec::Detailed(options) => {
+                let mut options = options.clone();
+                let field = format!("{}.command", field);
+                options.command = match &options.command {
+                    WrapperArgs::Line(line) => WrapperArgs::Line(self.wrapper_value(line, &field)?),
+                    WrapperArgs::Args(args) => WrapperArgs::Args(self.wrapper_list(args, &field)?),
+                };
+                WrapperSpec::Detailed(options)
+            }
+        };
+        Ok(expanded)
+    }
+
+    /// Expands a wrapper value, marking the braces a `p"..."` value leaves
+    /// so `expand_wrapper_tokens` does not read them as tokens.
+    fn wrapper_value(&self, value: &str, field: &str) -> Result<String, Box<dyn Error>> {
+        let expanded = self.value(value, field)?;
+        if !value.starts_with("p\"") {
+            return Ok(expanded);
+        }
+        Ok(expanded
+            .chars()
+            .map(|c| match c {
+                '{' => LITERAL_OPEN,
+                '}' => LITERAL_CLOSE,
+                c => c,
+            })
+            .collect())
+    }
+
+    fn wrapper_list(&self, values: &[String], field: &str) -> Result<Vec<String>, Box<dyn Error>> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| self.wrapper_value(value, &format!("{}[{}]", field, index)))
+            .collect()
     }
-    output.push_str(rest);
-    Ok(output)
 }
 
+/// Milliseconds from a bare number or one suffixed with `ms`, `s`, `m` or `h`.
+pub(crate) fn parse_duration_ms(text: &str) -> Option<u64> {
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number = number.parse::<u64>().ok()?;
+    let scale = match unit.trim() {
+        "" | "ms" => 1,
+        "s" => 1_000,
+        "m" => 60_000,
+        "h" => 3_600_000,
+        _ => return None,
+    };
+    number.checked_mul(scale)
+}
+
+/// Expands `expr`, falling back to `default` when `expr` cannot be resolved
+/// (unset env var, unknown or unfinished step, ...).
 fn expand_token(
-    token: &str,
-    pid_map: &HashMap<String, Vec<u32>>,
-) -> Result<String, Box<dyn std::error::Error>> {
-    let mut parts = token.splitn(2, ':');
-    let id = parts
-        .next()
-        .ok_or_else(|| ConfigError("placeholder missing id".to_string()))?;
-    let key = parts
-        .next()
-        .ok_or_else(|| ConfigError("placeholder missing key".to_string()))?;
-
-    if key == "pid" {
-        let pids = pid_map
-            .get(id)
-            .ok_or_else(|| ConfigError(format!("unknown id '{}'", id)))?;
-        let first = pids
-            .first()
-            .ok_or_else(|| ConfigError(format!("no pid for id '{}'", id)))?;
-        return Ok(first.to_string());
+    expr: &str,
+    default: Option<&str>,
+    ctx: &TemplateContext,
+) -> Result<String, Box<dyn Error>> {
+    if PROCESS_TOKENS.contains(&expr) {
+        return Ok(format!("{}{}{}", DEFER_OPEN, expr, DEFER_CLOSE));
     }
+    match evaluate(expr, ctx) {
+        Ok(value) => Ok(value),
+        Err(err) => default.map(str::to_string).ok_or(err),
+    }
+}
 
-    if key == "pid," {
-        let pids = pid_map
-            .get(id)
-            .ok_or_else(|| ConfigError(format!("unknown id '{}'", id)))?;
-        let joined = pids
-            .iter()
+#[derive(Clone, Debug, PartialEq)]
+enum Tok {
+    Num(i64),
+    Ref(String),
+    Op(char),
+}
+
+/// A lone reference expands to its text; anything else is integer arithmetic
+/// with `+ - * / %` and parentheses. `-` inside a name (step ids) is part of
+/// the name, so subtraction needs surrounding spaces: `{nproc - 1}`.
+fn evaluate(expr: &str, ctx: &TemplateContext) -> Result<String, Box<dyn Error>> {
+    let tokens = tokenize(expr)?;
+    if let [Tok::Ref(name)] = tokens.as_slice() {
+        return resolve_ref(name, ctx);
+    }
+    let mut parser = ExprParser {
+        tokens: &tokens,
+        pos: 0,
+        ctx,
+    };
+    let value = parser.expr()?;
+    if parser.pos != tokens.len() {
+        return Err(Box::new(ConfigError(format!(
+            "unexpected trailing input in placeholder '{}'",
+            expr
+        ))));
+    }
+    Ok(value.to_string())
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Tok>, Box<dyn Error>> {
+    let chars = expr.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+            let text = chars[start..i].iter().collect::<String>();
+            tokens.push(Tok::Num(text.parse()?));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || "_.-:,".contains(chars[i])) {
+                i += 1;
+            }
+            tokens.push(Tok::Ref(chars[start..i].iter().collect()));
+        } else if "+-*/%()".contains(c) {
+            tokens.push(Tok::Op(c));
+            i += 1;
+        } else {
+            return Err(Box::new(ConfigError(format!(
+                "unexpected '{}' in placeholder '{}'",
+                c, expr
+            ))));
+        }
+    }
+    if tokens.is_empty() {
+        return Err(Box::new(ConfigError("empty placeholder".to_string())));
+    }
+    Ok(tokens)
+}
+
+struct ExprParser<'a> {
+    tokens: &'a [Tok],
+    pos: usize,
+    ctx: &'a TemplateContext<'a>,
+}
+
+impl ExprParser<'_> {
+    fn expr(&mut self) -> Result<i64, Box<dyn Error>> {
+        let mut value = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek_op() {
+            self.pos += 1;
+            let rhs = self.term()?;
+            let result = if op == '+' {
+                value.checked_add(rhs)
+            } else {
+                value.checked_sub(rhs)
+            };
+            value = result.ok_or_else(overflow)?;
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> Result<i64, Box<dyn Error>> {
+        let mut value = self.factor()?;
+        while let Some(op @ ('*' | '/' | '%')) = self.peek_op() {
+            self.pos += 1;
+            let rhs = self.factor()?;
+            if op != '*' && rhs == 0 {
+                return Err(Box::new(ConfigError(
+                    "division by zero in placeholder".to_string(),
+                )));
+            }
+            let result = match op {
+                '*' => value.checked_mul(rhs),
+                '/' => value.checked_div(rhs),
+                _ => value.checked_rem(rhs),
+            };
+            value = result.ok_or_else(overflow)?;
+        }
+        Ok(value)
+    }
+
+    fn factor(&mut self) -> Result<i64, Box<dyn Error>> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Tok::Num(value)) => Ok(value),
+            Some(Tok::Ref(name)) => {
+                let text = resolve_ref(&name, self.ctx)?;
+                text.trim().parse::<i64>().map_err(|_| {
+                    Box::new(ConfigError(format!(
+                        "'{}' is not a number ('{}')",
+                        name, text
+                    ))) as Box<dyn Error>
+                })
+            }
+            Some(Tok::Op('-')) => self.factor()?.checked_neg().ok_or_else(overflow),
+            Some(Tok::Op('(')) => {
+                let value = self.expr()?;
+                if self.peek_op() != Some(')') {
+                    return Err(Box::new(ConfigError(
+                        "missing ')' in placeholder".to_string(),
+                    )));
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            _ => Err(Box::new(ConfigError(
+                "incomplete placeholder expression".to_string(),
+            ))),
+        }
+    }
+
+    fn peek_op(&self) -> Option<char> {
+        match self.tokens.get(self.pos) {
+            Some(Tok::Op(op)) => Some(*op),
+            _ => None,
+        }
+    }
+}
+
+fn overflow() -> Box<dyn Error> {
+    Box::new(ConfigError(
+        "arithmetic overflow in placeholder".to_string(),
+    ))
+}
+
+fn resolve_ref(name: &str, ctx: &TemplateContext) -> Result<String, Box<dyn Error>> {
+    match name {
+        "nproc" => return Ok(host_cpus().to_string()),
+        "mem_mb" => return Ok((host_mem_bytes()? / (1024 * 1024)).to_string()),
+        "mem_bytes" => return Ok(host_mem_bytes()?.to_string()),
+        "run_id" => return Ok(ctx.run_id.to_string()),
+        "run_dir" => return Ok(ctx.run_dir.to_string_lossy().to_string()),
+        "step_id" => return Ok(ctx.step_id.to_string()),
+        "start_ms" => return Ok(ctx.started_at_ms.to_string()),
+        "proc_index" => {
+            return Err(Box::new(ConfigError(
+                "{proc_index} must be used on its own".to_string(),
+            )));
+        }
+        _ => {}
+    }
+
+    if let Some(var) = name.strip_prefix("var:") {
+        return ctx
+            .vars
+            .get(var)
+            .cloned()
+            .ok_or_else(|| Box::new(ConfigError(format!("var '{}' is not defined", var))) as _);
+    }
+
+    if let Some(var) = name.strip_prefix("env:") {
+        return std::env::var(var)
+            .map_err(|_| Box::new(ConfigError(format!("env var '{}' is not set", var))) as _);
+    }
+
+    let (id, key) = name
+        .rsplit_once(':')
+        .ok_or_else(|| ConfigError(format!("unknown placeholder '{}'", name)))?;
+    let facts = ctx
+        .steps
+        .get(id)
+        .ok_or_else(|| ConfigError(format!("unknown id '{}'", id)))?;
+
+    match key {
+        "pid" => facts
+            .pids
+            .first()
             .map(|pid| pid.to_string())
-            .collect::<Vec<_>>()
-            .join(",");
-        return Ok(joined);
+            .ok_or_else(|| Box::new(ConfigError(format!("no pid for id '{}'", id))) as _),
+        "pid," => Ok(join(&facts.pids)),
+        "exit" | "exit," if !facts.finished => Err(Box::new(ConfigError(format!(
+            "step '{}' has not exited",
+            id
+        )))),
+        "exit" => facts
+            .exit_codes
+            .first()
+            .map(|code| code.to_string())
+            .ok_or_else(|| Box::new(ConfigError(format!("no exit code for id '{}'", id))) as _),
+        "exit," => Ok(join(&facts.exit_codes)),
+        "start_ms" => facts
+            .started_at_ms
+            .map(|ts| ts.to_string())
+            .ok_or_else(|| Box::new(ConfigError(format!("step '{}' has not started", id))) as _),
+        _ => Err(Box::new(ConfigError(format!(
+            "unknown placeholder key '{}'",
+            key
+        )))),
     }
+}
 
-    Err(Box::new(ConfigError(format!(
-        "unknown placeholder key '{}'",
-        key
-    ))))
+fn join<T: ToString>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn host_cpus() -> usize {
+    std::thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(1)
+}
+
+fn host_mem_bytes() -> Result<u64, Box<dyn Error>> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo")?;
+    let kb = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|value| value.parse::<u64>().ok())
+        .ok_or_else(|| ConfigError("MemTotal not found in /proc/meminfo".to_string()))?;
+    Ok(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn literal(text: &str) -> Segment {
+        Segment::Literal(text.to_string())
+    }
+
+    fn placeholder(expr: &str, default: Option<&str>, column: usize) -> Segment {
+        Segment::Placeholder {
+            expr: expr.to_string(),
+            default: default.map(str::to_string),
+            column,
+        }
+    }
+
+    fn parse_error(body: &str) -> (usize, String) {
+        let err = parse_template(body, 3).unwrap_err();
+        (err.column, err.message)
+    }
+
+    /// Expands `value` as the `command` of step `s`; step `a` has started
+    /// but not exited.
+    fn expand(value: &str) -> Result<String, String> {
+        with_expander(|expander| expander.value(value, "command"))
+    }
+
+    /// Expands `value` as a wrapper of step `s` for process 3, in a run
+    /// directory whose name looks like a token.
+    fn expand_wrapper(value: &str) -> String {
+        let value = with_expander(|expander| expander.wrapper_value(value, "wrapper")).unwrap();
+        let vars = ProcessVars {
+            step_id: "s",
+            run_dir: Path::new("/tmp/{step_id}"),
+            proc_index: 3,
+        };
+        expand_wrapper_tokens(&value, &vars)
+    }
+
+    fn with_expander(
+        expand: impl FnOnce(&Expander) -> Result<String, Box<dyn Error>>,
+    ) -> Result<String, String> {
+        let steps = HashMap::from([(
+            "a".to_string(),
+            StepFacts {
+                pids: vec![10, 11],
+                started_at_ms: Some(7),
+                ..StepFacts::default()
+            },
+        )]);
+        let vars = HashMap::from([("name".to_string(), "wl".to_string())]);
+        let ctx = TemplateContext {
+            steps: &steps,
+            run_id: "r1",
+            run_dir: Path::new("/tmp/{step_id}"),
+            step_id: "s",
+            started_at_ms: 5,
+            vars: &vars,
+        };
+        expand(&Expander { ctx: &ctx }).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn escaped_braces_are_literal() {
+        assert_eq!(parse_template("a{{b}}c", 3), Ok(vec![literal("a{b}c")]));
+        assert_eq!(
+            parse_template("{{{x}}}", 3),
+            Ok(vec![literal("{"), placeholder("x", None, 5), literal("}"),])
+        );
+    }
+
+    #[test]
+    fn placeholders_record_their_column() {
+        assert_eq!(
+            parse_template("ab { x } c", 3),
+            Ok(vec![
+                literal("ab "),
+                placeholder("x", None, 6),
+                literal(" c"),
+            ])
+        );
+    }
+
+    #[test]
+    fn defaults_may_be_quoted() {
+        assert_eq!(
+            parse_template("{env:X|plain}", 3),
+            Ok(vec![placeholder("env:X", Some("plain"), 3)])
+        );
+        assert_eq!(
+            parse_template("{env:X|}", 3),
+            Ok(vec![placeholder("env:X", Some(""), 3)])
+        );
+        assert_eq!(
+            parse_template(r#"{env:X|"a } | b"}"#, 3),
+            Ok(vec![placeholder("env:X", Some("a } | b"), 3)])
+        );
+        assert_eq!(
+            parse_template(r#"{env:X|'it\'s'}"#, 3),
+            Ok(vec![placeholder("env:X", Some("it's"), 3)])
+        );
+    }
+
+    #[test]
+    fn unbalanced_braces_are_errors() {
+        assert_eq!(
+            parse_error("ab}"),
+            (
+                5,
+                "unmatched '}' (use '}}' for a literal brace)".to_string()
+            )
+        );
+        assert_eq!(parse_error("x{y"), (4, "unclosed '{'".to_string()));
+        assert_eq!(
+            parse_error("{a{b}}"),
+            (5, "unexpected '{' inside placeholder".to_string())
+        );
+        assert_eq!(parse_error("{ }"), (3, "empty placeholder".to_string()));
+        assert_eq!(
+            parse_error("{env:X|\"ab}"),
+            (10, "unterminated \" in default".to_string())
+        );
+        assert_eq!(
+            parse_error("{env:X|\"a\" b}"),
+            (3, "unclosed '{'".to_string())
+        );
+    }
+
+    #[test]
+    fn values_without_p_prefix_are_untouched() {
+        assert_eq!(expand("echo {nproc}"), Ok("echo {nproc}".to_string()));
+    }
+
+    #[test]
+    fn p_literals_expand_placeholders() {
+        assert_eq!(
+            expand(r#"p"{step_id}@{run_id} {var:name} {{x}} {a:pid,}""#),
+            Ok("s@r1 wl {x} 10,11".to_string())
+        );
+        assert_eq!(expand(r#"p"{a:start_ms}""#), Ok("7".to_string()));
+    }
+
+    #[test]
+    fn p_literal_without_closing_quote_is_an_error() {
+        assert_eq!(
+            expand(r#"p"{step_id}"#),
+            Err("step 's' field 'command' column 11: placeholder missing closing '\"'".to_string())
+        );
+    }
+
+    #[test]
+    fn defaults_replace_unresolved_placeholders() {
+        assert_eq!(expand(r#"p"{var:missing|none}""#), Ok("none".to_string()));
+        assert_eq!(expand(r#"p"{a:exit|running}""#), Ok("running".to_string()));
+        assert_eq!(expand(r#"p"{var:name|none}""#), Ok("wl".to_string()));
+    }
+
+    #[test]
+    fn unknown_names_cite_their_column() {
+        assert_eq!(
+            expand(r#"p"x {bogus}""#),
+            Err("step 's' field 'command' column 5: unknown placeholder 'bogus'".to_string())
+        );
+        assert_eq!(
+            expand(r#"p"{b:pid}""#),
+            Err("step 's' field 'command' column 3: unknown id 'b'".to_string())
+        );
+        assert_eq!(
+            expand(r#"p"{a:bogus}""#),
+            Err("step 's' field 'command' column 3: unknown placeholder key 'bogus'".to_string())
+        );
+        assert_eq!(
+            expand(r#"p"{var:missing}""#),
+            Err("step 's' field 'command' column 3: var 'missing' is not defined".to_string())
+        );
+    }
+
+    #[test]
+    fn arithmetic_follows_precedence() {
+        assert_eq!(
+            expand(r#"p"{1 + 2 * 3} {(1 + 2) * 3} {7 % 4 - -1}""#),
+            Ok("7 9 4".to_string())
+        );
+        assert_eq!(
+            expand(r#"p"{1 / 0}""#),
+            Err("step 's' field 'command' column 3: division by zero in placeholder".to_string())
+        );
+    }
+
+    #[test]
+    fn arithmetic_overflow_is_an_error() {
+        for expr in [
+            "9223372036854775807 + 1",
+            "3 * 9223372036854775807",
+            "(0 - 9223372036854775807 - 1) / (0 - 1)",
+            "(0 - 9223372036854775807 - 1) % (0 - 1)",
+        ] {
+            assert_eq!(
+                expand(&format!("p\"{{{}}}\"", expr)),
+                Err(
+                    "step 's' field 'command' column 3: arithmetic overflow in placeholder"
+                        .to_string()
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn wrapper_tokens_expand_once() {
+        assert_eq!(expand_wrapper("taskset -c {proc_index}"), "taskset -c 3");
+        assert_eq!(expand_wrapper("{run_dir}/{step_id}"), "/tmp/{step_id}/s");
+        assert_eq!(
+            expand_wrapper("p\"{run_dir}/{proc_index}\""),
+            "/tmp/{step_id}/3"
+        );
+        assert_eq!(expand_wrapper("p\"{var:name}-{proc_index}\""), "wl-3");
+    }
+
+    #[test]
+    fn wrapper_escapes_stay_literal() {
+        assert_eq!(expand_wrapper("p\"{{proc_index}}\""), "{proc_index}");
+        assert_eq!(
+            expand_wrapper("p\"awk '{{print {proc_index}}}'\""),
+            "awk '{print 3}'"
+        );
+        assert_eq!(expand_wrapper("{unknown} {proc_index}"), "{unknown} 3");
+    }
 }
@@ -1,4 +1,8 @@
-use crate::orchestrator::config::Step;
+use crate::orchestrator::config::{ConfigError, Step, WrapperArgs, WrapperSpec, step_wrappers};
+use crate::orchestrator::credentials::setpriv_wrapper;
+use crate::orchestrator::profile::profile_wrapper;
+use crate::orchestrator::templating::{ProcessVars, expand_process_tokens, expand_wrapper_tokens};
+use std::error::Error;
 use std::process::Command;
 
 pub(crate) struct WrappedCommand {
@@ -6,29 +10,57 @@ pub(crate) struct WrappedCommand {
     pub(crate) display: String,
 }
 
-pub(crate) fn wrap_command(step: &Step, base: &[String]) -> WrappedCommand {
-    if let Some(wrapper) = step.wrapper.as_deref() {
-        if wrapper.trim().is_empty() {
-            return base_command(base);
+/// How the wrapped command is passed to the wrapper.
+#[derive(Clone, Copy, PartialEq)]
+enum AppendMode {
+    /// `wrapper -- cmd args...` (default)
+    Separator,
+    /// `wrapper cmd args...` for tools such as taskset, numactl, chrt, ionice
+    Direct,
+    /// `wrapper 'cmd args...'` as one shell-quoted argument, e.g. `sh -c`
+    String,
+}
+
+/// Composes the step's wrappers around `base`, the first wrapper outermost.
+pub(crate) fn wrap_command(
+    step: &Step,
+    base: &[String],
+    vars: &ProcessVars,
+) -> Result<WrappedCommand, Box<dyn Error>> {
+    let mut wrappers = step_wrappers(step).into_iter().cloned().collect::<Vec<_>>();
+    if let Some(profile) = &step.profile {
+        // The profiler sits innermost so user wrappers (taskset, ...) apply to it too.
+        wrappers.push(profile_wrapper(profile)?);
+    }
+    if let Some(setpriv) = setpriv_wrapper(step)? {
+        // Innermost of all, so only the workload runs with the step's credentials.
+        wrappers.push(setpriv);
+    }
+
+    let mut argv = base
+        .iter()
+        .map(|arg| expand_process_tokens(arg, vars))
+        .collect::<Vec<_>>();
+    for wrapper in wrappers.iter().rev() {
+        let parts = wrapper_argv(wrapper)?
+            .iter()
+            .map(|part| expand_wrapper_tokens(part, vars))
+            .collect::<Vec<_>>();
+        if parts.is_empty() {
+            continue;
         }
-        let mut wrapper_parts = split_args(wrapper);
-        let wrapper_display = wrapper_parts.join(" ");
-        let mut command = Command::new(
-            wrapper_parts
-                .get(0)
-                .map(|value| value.as_str())
-                .unwrap_or(wrapper),
-        );
-        if wrapper_parts.len() > 1 {
-            command.args(&wrapper_parts[1..]);
+        let inner = argv;
+        argv = parts;
+        match append_mode(wrapper)? {
+            AppendMode::Separator => {
+                argv.push("--".to_string());
+                argv.extend(inner);
+            }
+            AppendMode::Direct => argv.extend(inner),
+            AppendMode::String => argv.push(join_quoted(&inner)),
         }
-        command.arg("--");
-        command.args(base);
-        let display = format!("{} -- {}", wrapper_display, base.join(" "));
-        return WrappedCommand { command, display };
     }
-
-    base_command(base)
+    Ok(base_command(&argv))
 }
 
 fn base_command(base: &[String]) -> WrappedCommand {
@@ -38,14 +70,128 @@ fn base_command(base: &[String]) -> WrappedCommand {
     }
     WrappedCommand {
         command,
-        display: base.join(" "),
+        display: join_quoted(base),
+    }
+}
+
+fn wrapper_argv(wrapper: &WrapperSpec) -> Result<Vec<String>, Box<dyn Error>> {
+    let args = match wrapper {
+        WrapperSpec::Line(line) => return split_args(line),
+        WrapperSpec::Args(args) => return Ok(args.clone()),
+        WrapperSpec::Detailed(options) => &options.command,
+    };
+    match args {
+        WrapperArgs::Line(line) => split_args(line),
+        WrapperArgs::Args(args) => Ok(args.clone()),
+    }
+}
+
+fn append_mode(wrapper: &WrapperSpec) -> Result<AppendMode, Box<dyn Error>> {
+    let value = match wrapper {
+        WrapperSpec::Detailed(options) => options.append.as_deref(),
+        _ => None,
+    };
+    match value.unwrap_or("separator") {
+        "separator" => Ok(AppendMode::Separator),
+        "direct" => Ok(AppendMode::Direct),
+        "string" => Ok(AppendMode::String),
+        other => Err(Box::new(ConfigError(format!(
+            "wrapper.append must be 'separator', 'direct' or 'string' (got '{}')",
+            other
+        )))),
     }
 }
 
-fn split_args(value: &str) -> Vec<String> {
-    value
-        .split_whitespace()
-        .filter(|part| !part.is_empty())
-        .map(|part| part.to_string())
-        .collect()
+/// Splits `value` into words using POSIX shell quoting rules: single quotes
+/// are literal, double quotes allow `\\` escapes of `$`, `` ` ``, `"`, `\\`,
+/// and a backslash outside quotes escapes the next character. No expansion
+/// is performed.
+pub(crate) fn split_args(value: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut chars = value.chars();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => current.push(c),
+                        None => return Err(unterminated(value, "'")),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('$' | '`' | '"' | '\\')) => current.push(c),
+                            Some('\n') => {}
+                            Some(c) => {
+                                current.push('\\');
+                                current.push(c);
+                            }
+                            None => return Err(unterminated(value, "\"")),
+                        },
+                        Some(c) => current.push(c),
+                        None => return Err(unterminated(value, "\"")),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                match chars.next() {
+                    Some('\n') => {}
+                    Some(c) => current.push(c),
+                    None => current.push('\\'),
+                }
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                current.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(current);
+    }
+    Ok(words)
+}
+
+fn unterminated(value: &str, quote: &str) -> Box<dyn Error> {
+    Box::new(ConfigError(format!(
+        "wrapper '{}' has an unterminated {} quote",
+        value, quote
+    )))
+}
+
+/// Joins `args` into a string a POSIX shell would split back into `args`.
+pub(crate) fn join_quoted(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| quote_arg(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn quote_arg(arg: &str) -> String {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%^".contains(c));
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
 }